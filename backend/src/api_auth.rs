@@ -0,0 +1,394 @@
+use crate::atomic_file;
+use anyhow::{Context, Result, anyhow};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long a pairing code requested through the API stays valid
+const PAIRING_WINDOW: Duration = Duration::from_secs(300);
+
+/// Wrong codes accepted before an open pairing window is closed
+const MAX_PAIRING_ATTEMPTS: u32 = 5;
+
+/// How long the pairing window opened at startup refuses codes after too many wrong ones
+const PAIRING_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Earlier versions of the device tokens file that are kept next to it
+const BACKUPS: usize = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceToken {
+    pub device: String,
+    pub token: String,
+    /// Unix epoch (milliseconds) at which the device was paired
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PairedDevice {
+    pub device: String,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingCode {
+    pub code: String,
+    pub expires_in_s: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PairRequest {
+    pub code: String,
+    pub device: String,
+}
+
+/// Guards the HTTP control server with bearer tokens.
+///
+/// Tokens are either configured statically in the settings or issued per device through the
+/// pairing flow: an authenticated client opens a pairing window and shows the code, the new
+/// device exchanges that code for its own token. Device tokens are persisted in the data dir so
+/// they survive a restart. When authentication is disabled every request is allowed.
+pub struct ApiAuth {
+    enabled: bool,
+    static_tokens: Vec<String>,
+    path: PathBuf,
+    devices: Mutex<Vec<DeviceToken>>,
+    pairing: Mutex<Option<PairingWindow>>,
+    /// Why the file could not be read, it is left alone until it is fixed by hand
+    load_error: Option<String>,
+}
+
+struct PairingWindow {
+    code: String,
+    expires_at: Option<Instant>,
+    failed_attempts: u32,
+    /// Codes are refused until then, after too many wrong ones
+    locked_until: Option<Instant>,
+}
+
+impl ApiAuth {
+    pub fn new(enabled: bool, static_tokens: Vec<String>, path: PathBuf) -> Self {
+        let (devices, load_error) = match read_devices(&path) {
+            Ok(devices) => (devices, None),
+            Err(e) => {
+                let backups = atomic_file::backups(&path, BACKUPS);
+                log::error!(
+                    "Failed to load device tokens, devices cannot pair or be revoked until {} is fixed or restored from a backup ({}): {e:#}",
+                    path.display(),
+                    backups
+                        .iter()
+                        .map(|backup| backup.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                (Vec::new(), Some(format!("{e:#}")))
+            }
+        };
+
+        let auth = Self {
+            enabled,
+            static_tokens,
+            path,
+            devices: Mutex::new(devices),
+            pairing: Mutex::new(None),
+            load_error,
+        };
+
+        // Without any token nobody could open a pairing window, so open one until the first
+        // device has paired. Not when the devices could not be loaded, pairing would replace them.
+        if enabled
+            && auth.load_error.is_none()
+            && auth.static_tokens.is_empty()
+            && auth.devices.lock().unwrap().is_empty()
+        {
+            let code = auth.open_pairing(None);
+            log::warn!(
+                "API authentication is enabled but no tokens exist, pair a device with code {}",
+                code
+            );
+        }
+
+        auth
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_authorized(&self, token: Option<&str>) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let Some(token) = token else {
+            return false;
+        };
+
+        self.static_tokens
+            .iter()
+            .any(|known| constant_time_eq(known, token))
+            || self
+                .devices
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|device| constant_time_eq(&device.token, token))
+    }
+
+    pub fn start_pairing(&self) -> PairingCode {
+        let code = self.open_pairing(Some(Instant::now() + PAIRING_WINDOW));
+        log::info!("Opened a pairing window");
+        PairingCode {
+            code,
+            expires_in_s: PAIRING_WINDOW.as_secs(),
+        }
+    }
+
+    pub fn pair(&self, req: PairRequest) -> Result<DeviceToken> {
+        let device = req.device.trim();
+        if device.is_empty() {
+            anyhow::bail!("Device name is required");
+        }
+        self.ensure_writable()?;
+
+        {
+            let mut pairing = self.pairing.lock().unwrap();
+            let Some(window) = pairing.as_mut() else {
+                anyhow::bail!("No pairing in progress");
+            };
+            let now = Instant::now();
+            if window
+                .expires_at
+                .is_some_and(|expires_at| now >= expires_at)
+            {
+                *pairing = None;
+                anyhow::bail!("Pairing code expired");
+            }
+            if window
+                .locked_until
+                .is_some_and(|locked_until| now < locked_until)
+            {
+                anyhow::bail!("Too many invalid pairing codes, try again later");
+            }
+            if !constant_time_eq(&window.code, req.code.trim()) {
+                window.failed_attempts += 1;
+                if window.failed_attempts >= MAX_PAIRING_ATTEMPTS {
+                    if window.expires_at.is_some() {
+                        log::warn!("Closing pairing window after too many invalid codes");
+                        *pairing = None;
+                    } else {
+                        // Nothing could open a new window, so this one only pauses for a while
+                        log::warn!(
+                            "Refusing pairing codes for {}s after too many invalid codes",
+                            PAIRING_COOLDOWN.as_secs()
+                        );
+                        window.failed_attempts = 0;
+                        window.locked_until = Some(now + PAIRING_COOLDOWN);
+                    }
+                }
+                anyhow::bail!("Invalid pairing code");
+            }
+            *pairing = None;
+        }
+
+        let token = DeviceToken {
+            device: device.to_string(),
+            token: generate_token(),
+            created_at: epoch_millis(),
+        };
+
+        let mut devices = self.devices.lock().unwrap();
+        devices.retain(|existing| existing.device != token.device);
+        devices.push(token.clone());
+        self.persist(&devices)?;
+        log::info!("Paired device {}", token.device);
+        Ok(token)
+    }
+
+    pub fn devices(&self) -> Vec<PairedDevice> {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|device| PairedDevice {
+                device: device.device.clone(),
+                created_at: device.created_at,
+            })
+            .collect()
+    }
+
+    pub fn revoke(&self, device: &str) -> Result<()> {
+        let mut devices = self.devices.lock().unwrap();
+        let count = devices.len();
+        devices.retain(|existing| existing.device != device);
+        if devices.len() == count {
+            anyhow::bail!("Unknown device: {}", device);
+        }
+        self.persist(&devices)?;
+        log::info!("Revoked token of device {}", device);
+        Ok(())
+    }
+
+    fn open_pairing(&self, expires_at: Option<Instant>) -> String {
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        *self.pairing.lock().unwrap() = Some(PairingWindow {
+            code: code.clone(),
+            expires_at,
+            failed_attempts: 0,
+            locked_until: None,
+        });
+        code
+    }
+
+    /// Never overwrite a file that could not be read, the tokens of all paired devices would be
+    /// lost
+    fn ensure_writable(&self) -> Result<()> {
+        match &self.load_error {
+            Some(e) => Err(anyhow!(
+                "Device tokens are read-only because {} could not be loaded: {e}",
+                self.path.display()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn persist(&self, devices: &[DeviceToken]) -> Result<()> {
+        self.ensure_writable()?;
+        let json = serde_json::to_vec_pretty(devices)?;
+        atomic_file::write_private_with_backups(&self.path, &json, BACKUPS)
+    }
+}
+
+fn generate_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn epoch_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn read_devices(path: &PathBuf) -> Result<Vec<DeviceToken>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let devices = serde_json::from_slice(&bytes)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> ApiAuth {
+        let path = std::env::temp_dir()
+            .join(format!("carechords-auth-{}", rand::random::<u32>()))
+            .join("devices.json");
+        ApiAuth::new(true, Vec::new(), path)
+    }
+
+    fn pairing_code(auth: &ApiAuth) -> String {
+        auth.pairing.lock().unwrap().as_ref().unwrap().code.clone()
+    }
+
+    fn pair(auth: &ApiAuth, code: &str) -> Result<DeviceToken> {
+        auth.pair(PairRequest {
+            code: code.to_string(),
+            device: "phone".to_string(),
+        })
+    }
+
+    fn wrong_code(code: &str) -> String {
+        format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000)
+    }
+
+    #[test]
+    fn compares_tokens_in_full() {
+        assert!(constant_time_eq("secret-token", "secret-token"));
+        assert!(!constant_time_eq("secret-token", "secret-tokem"));
+        assert!(!constant_time_eq("secret-token", "secret"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+
+    #[test]
+    fn paired_devices_are_authorized() {
+        let auth = auth();
+        let code = pairing_code(&auth);
+
+        let token = pair(&auth, &code).unwrap();
+        assert!(auth.is_authorized(Some(&token.token)));
+        assert!(!auth.is_authorized(Some("not-a-token")));
+        assert!(!auth.is_authorized(None));
+        // A code pairs a single device
+        assert!(pair(&auth, &code).is_err());
+
+        fs::remove_dir_all(auth.path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn expired_pairing_codes_are_rejected() {
+        let auth = auth();
+        let code = auth.open_pairing(Some(Instant::now()));
+
+        assert!(pair(&auth, &code).is_err());
+        assert!(auth.pairing.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn too_many_wrong_codes_close_a_pairing_window() {
+        let auth = auth();
+        let code = auth.start_pairing().code;
+
+        for _ in 0..MAX_PAIRING_ATTEMPTS {
+            assert!(pair(&auth, &wrong_code(&code)).is_err());
+        }
+        assert!(pair(&auth, &code).is_err());
+        assert!(auth.pairing.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn the_startup_pairing_window_reopens_after_a_cooldown() {
+        let auth = auth();
+        let code = pairing_code(&auth);
+
+        for _ in 0..MAX_PAIRING_ATTEMPTS {
+            assert!(pair(&auth, &wrong_code(&code)).is_err());
+        }
+        assert!(pair(&auth, &code).is_err());
+
+        auth.pairing.lock().unwrap().as_mut().unwrap().locked_until = Some(Instant::now());
+        assert!(pair(&auth, &code).is_ok());
+
+        fs::remove_dir_all(auth.path.parent().unwrap()).unwrap();
+    }
+    #[test]
+    fn an_unreadable_token_file_is_never_overwritten() {
+        let dir = std::env::temp_dir().join(format!("carechords-auth-{}", rand::random::<u32>()));
+        let path = dir.join("devices.json");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "[{\"device\": ").unwrap();
+
+        let auth = ApiAuth::new(true, Vec::new(), path.clone());
+        assert!(auth.pairing.lock().unwrap().is_none());
+
+        let code = auth.start_pairing().code;
+        assert!(pair(&auth, &code).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[{\"device\": ");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,12 +1,28 @@
 use crate::data_paths;
-use anyhow::Result;
+use crate::music_timer::{Fade, FadeCurve};
+use crate::pipeline::rtsp_server::IpNetwork;
+use anyhow::{Context, Result};
 use clap::Parser;
-use config::{Config, Environment, File};
-use serde::{Deserialize, Serialize};
+use config::{Config, Environment, File, FileFormat};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::env;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize)]
+const CONFIG_PATHS: [&str; 3] = [
+    "/etc/carechords.toml",
+    "/usr/local/etc/carechords.toml",
+    "/opt/carechords/carechords.toml",
+];
+
+/// Environment variables of the nested rtsp_auth section
+const RTSP_AUTH_ENV_PREFIX: &str = "CARECHORDS_RTSP_AUTH__";
+
+/// Settings changed through the HTTP API, stored in the data dir on top of the config file
+const OVERRIDE_FILE: &str = "settings.toml";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApplicationSettings {
     /// The folder containing credentials.json, cache/, and music/
     #[serde(default = "default_data_dir")]
@@ -14,17 +30,52 @@ pub struct ApplicationSettings {
     /// The target RTSP server port
     #[serde(default = "default_rtsp_port")]
     pub rtsp_port: u16,
+    /// The address the HTTP control server binds to
+    #[serde(default = "default_http_bind")]
+    pub http_bind: String,
+    /// The HTTP control server port
+    #[serde(default = "default_http_port")]
+    pub http_port: u16,
+    /// Serve the HTTP API over TLS using this certificate (PEM), requires http_tls_key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_tls_cert: Option<String>,
+    /// The private key (PEM) belonging to http_tls_cert
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_tls_key: Option<String>,
+    /// Require a bearer token for every request to the HTTP control server
+    #[serde(default)]
+    pub api_auth: bool,
+    /// Tokens that are always accepted, besides the tokens issued to paired devices
+    #[serde(default, skip_serializing)]
+    pub api_tokens: Vec<String>,
+    /// The URL of the first monitor, kept for clients that only know a single monitor
+    #[serde(default)]
     pub monitor_url: String,
     /// Enable noise filtering
     #[serde(default)]
     pub noise_filter: bool,
+    /// The baby monitor cameras mixed into the stream, defaults to monitor_url when empty
+    #[serde(default)]
+    pub monitors: Vec<MonitorSettings>,
     #[serde(default)]
     pub local_audio: LocalAudioSettings,
     #[serde(default)]
     pub youtube_audio: LocalAudioSettings,
+    #[serde(default)]
+    pub sound_detection: SoundDetectionSettings,
+    #[serde(default)]
+    pub ducking: DuckingSettings,
+    #[serde(default)]
+    pub monitor_watchdog: MonitorWatchdogSettings,
+    #[serde(default)]
+    pub fade_in: FadeInSettings,
+    #[serde(default)]
+    pub rtsp_auth: RtspAuthSettings,
+    #[serde(default)]
+    pub rtsp_stream: RtspStreamSettings,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct LocalAudioSettings {
     #[serde(default = "default_local_roots")]
     pub roots: Vec<String>,
@@ -36,23 +87,275 @@ pub struct LocalAudioSettings {
 struct ConfigSettings {
     data_dir: Option<String>,
     rtsp_port: Option<u16>,
+    http_bind: Option<String>,
+    http_port: Option<u16>,
+    http_tls_cert: Option<String>,
+    http_tls_key: Option<String>,
+    #[serde(default)]
+    api_auth: bool,
+    #[serde(default, deserialize_with = "string_or_list")]
+    api_tokens: Vec<String>,
     monitor_url: Option<String>,
     #[serde(default)]
     noise_filter: bool,
     #[serde(default)]
+    monitors: Vec<MonitorSettings>,
+    #[serde(default)]
     local_audio: Option<LocalAudioSettings>,
     #[serde(default)]
     youtube_audio: Option<LocalAudioSettings>,
+    #[serde(default)]
+    sound_detection: SoundDetectionSettings,
+    #[serde(default)]
+    ducking: DuckingSettings,
+    #[serde(default)]
+    monitor_watchdog: MonitorWatchdogSettings,
+    #[serde(default)]
+    fade_in: FadeInSettings,
+    #[serde(default)]
+    rtsp_auth: RtspAuthSettings,
+    #[serde(default)]
+    rtsp_stream: RtspStreamSettings,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MonitorSettings {
+    pub name: String,
+    pub url: String,
+    /// Gain applied to this camera before it is mixed with the other monitors
+    #[serde(default = "default_monitor_gain")]
+    pub gain: f64,
+    /// Overrides the global noise_filter option for this camera
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noise_filter: Option<bool>,
+    /// Start with this camera muted
+    #[serde(default)]
+    pub muted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SoundDetectionSettings {
+    /// Loudness of the monitor audio (RMS, in dBFS) that counts as a sound
+    #[serde(default = "default_sound_threshold_db")]
+    pub threshold_db: f64,
+    /// How long the monitor has to stay above the threshold before an event is raised
+    #[serde(default = "default_sound_min_duration_ms")]
+    pub min_duration_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DuckingSettings {
+    /// Lower the music volume while the monitor is loud
+    #[serde(default)]
+    pub enabled: bool,
+    /// Loudness of the monitor audio (RMS, in dBFS) that triggers ducking
+    #[serde(default = "default_ducking_threshold_db")]
+    pub threshold_db: f64,
+    /// How much the music is attenuated while ducked, in dB
+    #[serde(default = "default_ducking_depth_db")]
+    pub depth_db: f64,
+    /// Time to fade the music down to the ducked volume
+    #[serde(default = "default_ducking_attack_ms")]
+    pub attack_ms: u64,
+    /// Time the music stays ducked after the monitor went quiet
+    #[serde(default = "default_ducking_hold_ms")]
+    pub hold_ms: u64,
+    /// Time to restore the music to its original volume
+    #[serde(default = "default_ducking_release_ms")]
+    pub release_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MonitorWatchdogSettings {
+    /// Raise an alarm when the monitor goes silent, e.g. because the camera stream died
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// How long the monitor has to be silent before the alarm is raised
+    #[serde(default = "default_watchdog_timeout_s")]
+    pub timeout_s: u64,
+    /// Peak level (in dBFS) below which the monitor counts as silent, a live camera always has
+    /// some background noise
+    #[serde(default = "default_watchdog_silence_db")]
+    pub silence_db: f64,
+    /// Play a chime in the mix while the alarm is raised
+    #[serde(default = "default_true")]
+    pub chime: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FadeInSettings {
+    /// Fade the music in over this many seconds when playback starts, 0 starts at full volume
+    #[serde(default)]
+    pub duration_s: f64,
+    #[serde(default = "default_fade_in_curve")]
+    pub curve: FadeCurve,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct RtspAuthSettings {
+    /// Require these credentials to listen to the RTSP stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub method: RtspAuthMethod,
+    /// Addresses or networks (CIDR notation) allowed to connect, all clients are allowed when empty
+    #[serde(default)]
+    pub allowed_addresses: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RtspAuthMethod {
+    Basic,
+    #[default]
+    Digest,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct RtspStreamSettings {
+    /// The mix of the monitor and the music, enabled at /sleep by default
+    #[serde(default)]
+    pub mixed: RtspMountSettings,
+    /// Only the baby monitor, disabled by default
+    #[serde(default)]
+    pub monitor: RtspMountSettings,
+    /// Only the music, disabled by default
+    #[serde(default)]
+    pub music: RtspMountSettings,
+    #[serde(default)]
+    pub codec: RtspCodec,
+    /// Encoder bitrate in bits per second, the encoder default is used when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u32>,
+    /// Sample rate of the stream, defaults to 48000 for Opus and 44100 otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct RtspMountSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount_path: Option<String>,
+}
+
+/// The signals that can be published as an RTSP mount
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtspMountKind {
+    Mixed,
+    Monitor,
+    Music,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RtspCodec {
+    #[default]
+    Aac,
+    Opus,
+    L16,
+}
+
+fn default_monitor_gain() -> f64 {
+    1.0
 }
 
 fn default_rtsp_port() -> u16 {
     8554
 }
 
+impl RtspMountKind {
+    fn default_path(&self) -> &'static str {
+        match self {
+            RtspMountKind::Mixed => "/sleep",
+            RtspMountKind::Monitor => "/monitor",
+            RtspMountKind::Music => "/music",
+        }
+    }
+}
+
+fn default_http_bind() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_http_port() -> u16 {
+    7755
+}
+
+/// Accept a list as well as a comma separated string, so the tokens can be set from the environment
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    let values = match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(value) => value.split(',').map(str::to_string).collect(),
+        StringOrList::List(values) => values,
+    };
+    Ok(values
+        .into_iter()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect())
+}
+
 fn default_data_dir() -> String {
     ".".to_string()
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_fade_in_curve() -> FadeCurve {
+    FadeCurve::EqualPower
+}
+
+fn default_watchdog_timeout_s() -> u64 {
+    30
+}
+
+fn default_watchdog_silence_db() -> f64 {
+    -90.0
+}
+
+fn default_sound_threshold_db() -> f64 {
+    -30.0
+}
+
+fn default_sound_min_duration_ms() -> u64 {
+    1500
+}
+
+fn default_ducking_threshold_db() -> f64 {
+    -35.0
+}
+
+fn default_ducking_depth_db() -> f64 {
+    12.0
+}
+
+fn default_ducking_attack_ms() -> u64 {
+    250
+}
+
+fn default_ducking_hold_ms() -> u64 {
+    3000
+}
+
+fn default_ducking_release_ms() -> u64 {
+    2000
+}
+
 fn default_local_roots() -> Vec<String> {
     vec!["music".to_string()]
 }
@@ -72,6 +375,191 @@ impl Default for LocalAudioSettings {
     }
 }
 
+impl Default for SoundDetectionSettings {
+    fn default() -> Self {
+        Self {
+            threshold_db: default_sound_threshold_db(),
+            min_duration_ms: default_sound_min_duration_ms(),
+        }
+    }
+}
+
+impl Default for DuckingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_db: default_ducking_threshold_db(),
+            depth_db: default_ducking_depth_db(),
+            attack_ms: default_ducking_attack_ms(),
+            hold_ms: default_ducking_hold_ms(),
+            release_ms: default_ducking_release_ms(),
+        }
+    }
+}
+
+impl Default for MonitorWatchdogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout_s: default_watchdog_timeout_s(),
+            silence_db: default_watchdog_silence_db(),
+            chime: true,
+        }
+    }
+}
+
+impl Default for FadeInSettings {
+    fn default() -> Self {
+        Self {
+            duration_s: 0.0,
+            curve: default_fade_in_curve(),
+        }
+    }
+}
+
+impl FadeInSettings {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=600.0).contains(&self.duration_s) {
+            anyhow::bail!("fade_in.duration_s must be between 0 and 600 seconds");
+        }
+        Ok(())
+    }
+
+    pub fn fade(&self) -> Fade {
+        Fade {
+            duration: Duration::from_secs_f64(self.duration_s),
+            curve: self.curve,
+        }
+    }
+}
+
+impl MonitorWatchdogSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.timeout_s == 0 {
+            anyhow::bail!("monitor_watchdog.timeout_s must be at least 1 second");
+        }
+        if !(-120.0..=0.0).contains(&self.silence_db) {
+            anyhow::bail!("monitor_watchdog.silence_db must be between -120 and 0 dBFS");
+        }
+        Ok(())
+    }
+}
+
+impl SoundDetectionSettings {
+    pub fn validate(&self) -> Result<()> {
+        if !self.threshold_db.is_finite() || self.threshold_db > 0.0 {
+            anyhow::bail!("sound_detection.threshold_db must not be above 0 dBFS");
+        }
+        if self.min_duration_ms > 60_000 {
+            anyhow::bail!("sound_detection.min_duration_ms must not be above a minute");
+        }
+        Ok(())
+    }
+}
+
+impl DuckingSettings {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=60.0).contains(&self.depth_db) {
+            anyhow::bail!("depth_db must be between 0 and 60");
+        }
+        if self.threshold_db.is_nan() || self.threshold_db > 0.0 {
+            anyhow::bail!("threshold_db must not be above 0 dBFS");
+        }
+        Ok(())
+    }
+}
+
+impl RtspAuthSettings {
+    pub fn validate(&self) -> Result<()> {
+        match (&self.username, &self.password) {
+            (None, None) => {}
+            (Some(username), Some(_)) if !username.is_empty() => {}
+            _ => anyhow::bail!("rtsp_auth requires both a username and a password"),
+        }
+        self.allowed_networks()?;
+        Ok(())
+    }
+
+    pub fn allowed_networks(&self) -> Result<Vec<IpNetwork>> {
+        self.allowed_addresses
+            .iter()
+            .map(|address| address.parse())
+            .collect()
+    }
+}
+
+impl RtspStreamSettings {
+    /// The enabled mounts with the path they are served at
+    pub fn enabled_mounts(&self) -> Vec<(RtspMountKind, String)> {
+        [
+            (RtspMountKind::Mixed, &self.mixed),
+            (RtspMountKind::Monitor, &self.monitor),
+            (RtspMountKind::Music, &self.music),
+        ]
+        .into_iter()
+        .filter(|(kind, mount)| mount.enabled.unwrap_or(*kind == RtspMountKind::Mixed))
+        .map(|(kind, mount)| {
+            let path = mount
+                .mount_path
+                .clone()
+                .unwrap_or_else(|| kind.default_path().to_string());
+            (kind, path)
+        })
+        .collect()
+    }
+
+    pub fn mount_enabled(&self, kind: RtspMountKind) -> bool {
+        self.enabled_mounts()
+            .iter()
+            .any(|(enabled, _)| *enabled == kind)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.unwrap_or(match self.codec {
+            RtspCodec::Opus => 48000,
+            RtspCodec::Aac | RtspCodec::L16 => 44100,
+        })
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let mounts = self.enabled_mounts();
+        if mounts.is_empty() {
+            anyhow::bail!("At least one RTSP mount must be enabled");
+        }
+        for (index, (_, path)) in mounts.iter().enumerate() {
+            if !path.starts_with('/') || path.len() < 2 {
+                anyhow::bail!(
+                    "RTSP mount path {} must start with / and name the stream",
+                    path
+                );
+            }
+            if mounts[..index].iter().any(|(_, other)| other == path) {
+                anyhow::bail!("RTSP mount path {} is used more than once", path);
+            }
+        }
+        if self.bitrate == Some(0) {
+            anyhow::bail!("rtsp_stream.bitrate must be above 0");
+        }
+        if self.codec == RtspCodec::L16 && self.bitrate.is_some() {
+            anyhow::bail!("rtsp_stream.bitrate can not be set for the uncompressed L16 codec");
+        }
+
+        let sample_rate = self.sample_rate();
+        let supported = match self.codec {
+            RtspCodec::Opus => [8000, 12000, 16000, 24000, 48000].contains(&sample_rate),
+            RtspCodec::Aac | RtspCodec::L16 => (8000..=96000).contains(&sample_rate),
+        };
+        if !supported {
+            anyhow::bail!(
+                "rtsp_stream.sample_rate {} is not supported by {:?}",
+                sample_rate,
+                self.codec
+            );
+        }
+        Ok(())
+    }
+}
+
 impl LocalAudioSettings {
     fn for_data_dir(data_dir: impl Into<PathBuf>) -> Self {
         Self {
@@ -107,12 +595,31 @@ struct Cli {
         help = "Set the target RTSP server port"
     )]
     rtsp_port: Option<u16>,
+    #[arg(
+        long = "http-bind",
+        help = "Set the address the HTTP control server binds to"
+    )]
+    http_bind: Option<String>,
+    #[arg(long = "http-port", help = "Set the HTTP control server port")]
+    http_port: Option<u16>,
+    #[arg(
+        long = "tls-cert",
+        help = "Serve the HTTP API over TLS using this PEM certificate"
+    )]
+    tls_cert: Option<String>,
+    #[arg(long = "tls-key", help = "Set the PEM private key for --tls-cert")]
+    tls_key: Option<String>,
     #[arg(
         short = 'm',
         long = "monitor-url",
         help = "Set the URL of the baby monitor"
     )]
     monitor_url: Option<String>,
+    #[arg(
+        long = "api-auth",
+        help = "Require a bearer token for the HTTP control server"
+    )]
+    api_auth: bool,
     #[arg(long = "noise-filter", help = "Enable noise filtering")]
     noise_filter: bool,
     #[arg(
@@ -124,10 +631,140 @@ struct Cli {
 
 impl ApplicationSettings {
     pub(crate) fn load() -> Result<Self> {
+        let settings = Self::load_with_cli(Cli::parse())?;
+        data_paths::set_data_dir(&settings.data_dir);
+
+        let toml_str =
+            toml::to_string_pretty(&settings).expect("Failed to convert settings to TOML format");
+        log::info!("Running with settings:\n{}", toml_str);
+        Ok(settings)
+    }
+
+    /// Read the settings again from the same sources as at startup
+    pub fn reload() -> Result<Self> {
         Self::load_with_cli(Cli::parse())
     }
 
+    /// Read the settings with these overrides (TOML) instead of the ones in the override file
+    pub fn with_overrides(overrides: &str) -> Result<Self> {
+        let cli = Cli::parse();
+        let loaded_settings = Self::read_sources(Some(overrides))?;
+        Self::from_sources(loaded_settings, cli)
+    }
+
+    /// The config files the settings are read from, including the override file
+    pub fn config_files() -> Vec<PathBuf> {
+        let mut files = match env::var("CARECHORDS_CONF") {
+            Ok(custom_conf) => vec![PathBuf::from(custom_conf)],
+            Err(_) => CONFIG_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .filter(|path| path.exists())
+                .collect(),
+        };
+        files.push(Self::override_file());
+        files
+    }
+
+    /// The file holding the settings changed through the HTTP API
+    pub fn override_file() -> PathBuf {
+        data_paths::data_dir().join(OVERRIDE_FILE)
+    }
+
+    pub fn http_address(&self) -> Result<SocketAddr> {
+        let ip = self
+            .http_bind
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow::anyhow!("Invalid http_bind address: {}", self.http_bind))?;
+        Ok(SocketAddr::new(ip, self.http_port))
+    }
+
+    /// The certificate and key to serve the HTTP API with, if TLS is enabled
+    pub fn http_tls(&self) -> Option<(PathBuf, PathBuf)> {
+        match (&self.http_tls_cert, &self.http_tls_key) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            _ => None,
+        }
+    }
+
+    /// Turn a single monitor_url into a monitor, and validate the configured monitors
+    fn resolve_monitors(&mut self) -> Result<()> {
+        if self.monitors.is_empty() {
+            if self.monitor_url.is_empty() {
+                anyhow::bail!(
+                    "monitor_url is required; set it in carechords.toml, CARECHORDS_MONITOR_URL, or --monitor-url"
+                );
+            }
+            self.monitors.push(MonitorSettings {
+                name: "monitor".to_string(),
+                url: self.monitor_url.clone(),
+                gain: default_monitor_gain(),
+                noise_filter: None,
+                muted: false,
+            });
+        } else if self.monitor_url.is_empty() {
+            self.monitor_url = self.monitors[0].url.clone();
+        }
+
+        for (index, monitor) in self.monitors.iter().enumerate() {
+            if monitor.name.trim().is_empty() || monitor.url.is_empty() {
+                anyhow::bail!("Every monitor requires a name and an url");
+            }
+            if self.monitors[..index]
+                .iter()
+                .any(|other| other.name == monitor.name)
+            {
+                anyhow::bail!("Monitor name {} is used more than once", monitor.name);
+            }
+            if !(0.0..=4.0).contains(&monitor.gain) {
+                anyhow::bail!(
+                    "Gain of monitor {} must be between 0.0 and 4.0",
+                    monitor.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_http(&self) -> Result<()> {
+        self.http_address()?;
+        match (&self.http_tls_cert, &self.http_tls_key) {
+            (None, None) => {}
+            (Some(cert), Some(key)) => {
+                for path in [cert, key] {
+                    if !Path::new(path).is_file() {
+                        anyhow::bail!("TLS file does not exist: {}", path);
+                    }
+                }
+            }
+            _ => anyhow::bail!("http_tls_cert and http_tls_key must be set together"),
+        }
+        Ok(())
+    }
+
     fn load_with_cli(cli: Cli) -> Result<Self> {
+        // The override file lives in the data dir, which itself is part of the settings
+        let data_dir = match &cli.data_dir {
+            Some(data_dir) => data_dir.clone(),
+            None => Self::read_sources(None)?
+                .data_dir
+                .unwrap_or_else(default_data_dir),
+        };
+        let override_file = Path::new(&data_dir).join(OVERRIDE_FILE);
+        let overrides = if override_file.is_file() {
+            Some(
+                std::fs::read_to_string(&override_file)
+                    .with_context(|| format!("Failed to read {}", override_file.display()))?,
+            )
+        } else {
+            None
+        };
+
+        let loaded_settings = Self::read_sources(overrides.as_deref())?;
+        Self::from_sources(loaded_settings, cli)
+    }
+
+    fn read_sources(overrides: Option<&str>) -> Result<ConfigSettings> {
         let mut config_builder = Config::builder();
 
         // Check for a user-specified config file from the CARECHORDS_CONF environment variable
@@ -144,13 +781,7 @@ impl ApplicationSettings {
             }
         } else {
             // Search in standard locations if CARECHORDS_CONF is not set
-            let config_paths = [
-                "/etc/carechords.toml",
-                "/usr/local/etc/carechords.toml",
-                "/opt/carechords/carechords.toml",
-            ];
-
-            for path in &config_paths {
+            for path in &CONFIG_PATHS {
                 if Path::new(path).exists() {
                     config_builder =
                         config_builder.add_source(File::with_name(path).required(false));
@@ -161,23 +792,58 @@ impl ApplicationSettings {
         // Load environment variables prefixed with CARECHORDS_
         config_builder = config_builder.add_source(Environment::with_prefix("CARECHORDS"));
 
+        // The RTSP access control settings can also be set from the environment, nested with a
+        // double underscore (e.g. CARECHORDS_RTSP_AUTH__USERNAME). The separator only applies to
+        // these variables, so it does not change how any other variable is read.
+        let rtsp_auth_vars = env::vars()
+            .filter(|(key, _)| key.starts_with(RTSP_AUTH_ENV_PREFIX))
+            .collect();
+        config_builder = config_builder.add_source(
+            Environment::with_prefix("CARECHORDS")
+                .prefix_separator("_")
+                .separator("__")
+                .source(Some(rtsp_auth_vars)),
+        );
+
+        // Settings changed through the HTTP API take precedence over the config file and the
+        // environment, CLI flags still override them
+        if let Some(overrides) = overrides {
+            config_builder = config_builder.add_source(File::from_str(overrides, FileFormat::Toml));
+        }
+
         // Build configuration. Required values may still be supplied by CLI flags,
         // so deserialize into an optional representation first.
-        let loaded_settings: ConfigSettings = config_builder.build()?.try_deserialize()?;
+        Ok(config_builder.build()?.try_deserialize()?)
+    }
+
+    fn from_sources(loaded_settings: ConfigSettings, cli: Cli) -> Result<Self> {
         let data_dir = loaded_settings.data_dir.unwrap_or_else(default_data_dir);
         let local_audio_configured = loaded_settings.local_audio.is_some();
         let youtube_audio_configured = loaded_settings.youtube_audio.is_some();
         let mut settings = ApplicationSettings {
             data_dir: data_dir.clone(),
             rtsp_port: loaded_settings.rtsp_port.unwrap_or_else(default_rtsp_port),
+            http_bind: loaded_settings.http_bind.unwrap_or_else(default_http_bind),
+            http_port: loaded_settings.http_port.unwrap_or_else(default_http_port),
+            http_tls_cert: loaded_settings.http_tls_cert,
+            http_tls_key: loaded_settings.http_tls_key,
+            api_auth: loaded_settings.api_auth,
+            api_tokens: loaded_settings.api_tokens,
             monitor_url: loaded_settings.monitor_url.unwrap_or_default(),
             noise_filter: loaded_settings.noise_filter,
+            monitors: loaded_settings.monitors,
             local_audio: loaded_settings
                 .local_audio
                 .unwrap_or_else(|| LocalAudioSettings::for_data_dir(&data_dir)),
             youtube_audio: loaded_settings
                 .youtube_audio
                 .unwrap_or_else(|| LocalAudioSettings::for_youtube_dir(&data_dir)),
+            sound_detection: loaded_settings.sound_detection,
+            ducking: loaded_settings.ducking,
+            monitor_watchdog: loaded_settings.monitor_watchdog,
+            fade_in: loaded_settings.fade_in,
+            rtsp_auth: loaded_settings.rtsp_auth,
+            rtsp_stream: loaded_settings.rtsp_stream,
         };
 
         // Override with CLI arguments if provided
@@ -193,25 +859,38 @@ impl ApplicationSettings {
         if let Some(rtsp_port) = cli.rtsp_port {
             settings.rtsp_port = rtsp_port;
         }
+        if let Some(http_bind) = cli.http_bind {
+            settings.http_bind = http_bind;
+        }
+        if let Some(http_port) = cli.http_port {
+            settings.http_port = http_port;
+        }
+        if let Some(tls_cert) = cli.tls_cert {
+            settings.http_tls_cert = Some(tls_cert);
+        }
+        if let Some(tls_key) = cli.tls_key {
+            settings.http_tls_key = Some(tls_key);
+        }
         if let Some(monitor_url) = cli.monitor_url {
             settings.monitor_url = monitor_url;
         }
+        if cli.api_auth {
+            settings.api_auth = true;
+        }
         // Only override noise_filter if the CLI flag is activated (true)
         if cli.noise_filter {
             settings.noise_filter = true;
         }
 
-        if settings.monitor_url.is_empty() {
-            anyhow::bail!(
-                "monitor_url is required; set it in carechords.toml, CARECHORDS_MONITOR_URL, or --monitor-url"
-            );
-        }
-
-        data_paths::set_data_dir(&settings.data_dir);
+        settings.sound_detection.validate()?;
+        settings.ducking.validate()?;
+        settings.monitor_watchdog.validate()?;
+        settings.fade_in.validate()?;
+        settings.validate_http()?;
+        settings.rtsp_auth.validate()?;
+        settings.rtsp_stream.validate()?;
 
-        let toml_str =
-            toml::to_string_pretty(&settings).expect("Failed to convert settings to TOML format");
-        log::info!("Running with settings:\n{}", toml_str);
+        settings.resolve_monitors()?;
         Ok(settings)
     }
 }
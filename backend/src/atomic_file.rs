@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Replace a file without ever leaving a half written one behind. The new content is written to a
+/// temporary file next to it and renamed over the old one, which is first kept as the newest of
+/// `backups` rolling backups (`<file>.1` being the newest).
+pub fn write_with_backups(path: &Path, content: &[u8], backups: usize) -> Result<()> {
+    write(path, content, backups, 0o666)
+}
+
+/// Like [`write_with_backups`], but only the owner may read or write the file, for secrets
+pub fn write_private_with_backups(path: &Path, content: &[u8], backups: usize) -> Result<()> {
+    write(path, content, backups, 0o600)
+}
+
+fn write(path: &Path, content: &[u8], backups: usize, mode: u32) -> Result<()> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+
+    let temp = sibling(path, "tmp");
+    // The mode only applies to a new file, not to one left behind by an earlier attempt
+    let _ = fs::remove_file(&temp);
+    {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&temp)
+            .with_context(|| format!("Failed to create {}", temp.display()))?;
+        file.write_all(content)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+    }
+
+    if backups > 0 && path.exists() {
+        rotate_backups(path, backups)?;
+    }
+
+    fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    // Make the rename itself durable
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// The backups of a file, newest first
+pub fn backups(path: &Path, backups: usize) -> Vec<PathBuf> {
+    (1..=backups)
+        .map(|index| backup_path(path, index))
+        .filter(|backup| backup.exists())
+        .collect()
+}
+
+fn rotate_backups(path: &Path, backups: usize) -> Result<()> {
+    for index in (1..backups).rev() {
+        let from = backup_path(path, index);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, index + 1))
+                .with_context(|| format!("Failed to rotate backup {}", from.display()))?;
+        }
+    }
+    // A copy instead of a rename, so the file itself is never missing
+    fs::copy(path, backup_path(path, 1))
+        .with_context(|| format!("Failed to back up {}", path.display()))?;
+    Ok(())
+}
+
+fn backup_path(path: &Path, index: usize) -> PathBuf {
+    sibling(path, &index.to_string())
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{suffix}"));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_previous_versions_as_rolling_backups() {
+        let dir = std::env::temp_dir().join(format!("carechords-atomic-{}", rand::random::<u32>()));
+        let path = dir.join("playlists.json");
+
+        for version in 1..=4 {
+            write_with_backups(&path, version.to_string().as_bytes(), 2).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "4");
+        let backups = backups(&path, 2);
+        assert_eq!(backups.len(), 2);
+        assert_eq!(fs::read_to_string(&backups[0]).unwrap(), "3");
+        assert_eq!(fs::read_to_string(&backups[1]).unwrap(), "2");
+        assert!(!sibling(&path, "tmp").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn private_files_are_only_readable_by_their_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("carechords-atomic-{}", rand::random::<u32>()));
+        let path = dir.join("device_tokens.json");
+
+        write_private_with_backups(&path, b"[]", 1).unwrap();
+        write_private_with_backups(&path, b"[]", 1).unwrap();
+
+        for file in [path.clone(), backup_path(&path, 1)] {
+            let mode = fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -24,6 +24,10 @@ pub fn credentials_file() -> PathBuf {
     data_dir().join("credentials.json")
 }
 
+pub fn device_tokens_file() -> PathBuf {
+    data_dir().join("device_tokens.json")
+}
+
 pub fn legacy_credentials_file() -> PathBuf {
     cache_dir().join("credentials.json")
 }
@@ -35,3 +39,15 @@ pub fn playlist_metadata_cache_dir() -> PathBuf {
 pub fn system_playlists_file() -> PathBuf {
     cache_dir().join("system_playlists.json")
 }
+
+pub fn volume_file() -> PathBuf {
+    data_dir().join("volume.json")
+}
+
+pub fn play_order_file() -> PathBuf {
+    data_dir().join("play_order.json")
+}
+
+pub fn routines_file() -> PathBuf {
+    data_dir().join("routines.json")
+}
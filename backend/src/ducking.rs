@@ -0,0 +1,161 @@
+use crate::app_settings::DuckingSettings;
+use crate::music_timer::MusicVolume;
+use crate::sound_detector::MonitorLoudness;
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{Instant, MissedTickBehavior, interval};
+
+const ENVELOPE_TICK: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuckingStatus {
+    #[serde(flatten)]
+    pub settings: DuckingSettings,
+    /// The gain currently applied to the music by the ducking envelope
+    pub gain: f64,
+}
+
+/// Lowers the music while the baby monitor is loud.
+///
+/// The controller follows the loudness of the monitor branch and drives a separate gain that the
+/// `AudioBridge` applies on top of the music volume, so it never interferes with the sleep timer
+/// fade. The gain follows an attack/hold/release envelope: it fades down to the ducked level when
+/// the monitor gets loud, stays there for the hold time after it went quiet, and then fades back.
+pub struct DuckingController {
+    settings: Mutex<DuckingSettings>,
+    gain: Arc<MusicVolume>,
+}
+
+impl DuckingController {
+    pub fn new(settings: &DuckingSettings, gain: Arc<MusicVolume>) -> Self {
+        Self {
+            settings: Mutex::new(settings.clone()),
+            gain,
+        }
+    }
+
+    pub fn status(&self) -> DuckingStatus {
+        DuckingStatus {
+            settings: self.settings.lock().unwrap().clone(),
+            gain: self.gain.get_volume(),
+        }
+    }
+
+    pub fn update_settings(&self, settings: DuckingSettings) -> Result<DuckingStatus> {
+        settings.validate()?;
+        log::info!("Updating ducking settings: {:?}", settings);
+        *self.settings.lock().unwrap() = settings;
+        Ok(self.status())
+    }
+
+    pub fn start(self: &Arc<Self>, mut loudness: watch::Receiver<MonitorLoudness>) {
+        let controller = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = interval(ENVELOPE_TICK);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            let mut last_loud: Option<Instant> = None;
+
+            loop {
+                ticker.tick().await;
+                let settings = controller.settings.lock().unwrap().clone();
+                let now = Instant::now();
+
+                // Only fresh measurements count, the pipeline stops reporting when it is down
+                if settings.enabled
+                    && loudness.has_changed().unwrap_or(false)
+                    && loudness.borrow_and_update().rms_db >= settings.threshold_db
+                {
+                    last_loud = Some(now);
+                }
+
+                let ducked = settings.enabled
+                    && last_loud.is_some_and(|last_loud| {
+                        now.duration_since(last_loud) < Duration::from_millis(settings.hold_ms)
+                    });
+                let target = if ducked {
+                    db_to_gain(-settings.depth_db)
+                } else {
+                    1.0
+                };
+
+                let current = controller.gain.get_volume();
+                let next = envelope_step(current, target, &settings, ENVELOPE_TICK);
+                if next != current {
+                    controller.gain.set_volume(next);
+                }
+            }
+        });
+    }
+}
+
+/// Move the gain one tick towards the target, using the attack time when fading down and the
+/// release time when fading back up. Both times describe a full fade between unity gain and the
+/// ducked level.
+fn envelope_step(current: f64, target: f64, settings: &DuckingSettings, tick: Duration) -> f64 {
+    let range = 1.0 - db_to_gain(-settings.depth_db);
+    let fade_ms = if target < current {
+        settings.attack_ms
+    } else {
+        settings.release_ms
+    };
+
+    if fade_ms == 0 || range <= 0.0 {
+        return target;
+    }
+
+    let step = range * tick.as_millis() as f64 / fade_ms as f64;
+    if target < current {
+        (current - step).max(target)
+    } else {
+        (current + step).min(target)
+    }
+}
+
+fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> DuckingSettings {
+        DuckingSettings {
+            enabled: true,
+            depth_db: 20.0,
+            attack_ms: 100,
+            release_ms: 1000,
+            ..DuckingSettings::default()
+        }
+    }
+
+    #[test]
+    fn attack_reaches_ducked_level_within_attack_time() {
+        let settings = settings();
+        let mut gain = 1.0;
+        for _ in 0..5 {
+            gain = envelope_step(gain, 0.1, &settings, Duration::from_millis(20));
+        }
+        assert!((gain - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn release_is_slower_than_attack() {
+        let settings = settings();
+        let gain = envelope_step(0.1, 1.0, &settings, Duration::from_millis(100));
+        assert!((gain - 0.19).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_a_threshold_that_is_not_a_number() {
+        let settings = DuckingSettings {
+            threshold_db: f64::NAN,
+            ..settings()
+        };
+        assert!(settings.validate().is_err());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::app_settings::LocalAudioSettings;
+use crate::music_timer::TrackSleepTimer;
+use crate::play_order::{PlayOrder, RepeatMode, shuffle_from};
 use crate::spotify_player::{MusicMetadata, SpotifyPlayerInfo, SpotifyPlayerState};
 use crate::spotify_sink::SinkEvent;
 use anyhow::{Context, Result, anyhow};
@@ -7,6 +9,7 @@ use gstreamer::prelude::{Cast, ElementExt};
 use gstreamer_app::AppSink;
 use gstreamer_app::prelude::*;
 use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering as CmpOrdering;
 use std::collections::HashSet;
@@ -14,8 +17,9 @@ use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::SyncSender;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions};
 use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
@@ -23,6 +27,7 @@ use symphonia::core::probe::Hint;
 use tokio::sync::watch;
 
 const LOCAL_FILE_PLAYBACK_VOLUME: f64 = 0.35;
+const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocalAudioEntry {
@@ -65,8 +70,8 @@ pub enum LocalAudioEntryKind {
 
 #[derive(Clone)]
 pub struct LocalAudioLibrary {
-    roots: Arc<Vec<PathBuf>>,
-    allowed_extensions: Arc<HashSet<String>>,
+    roots: Arc<RwLock<Arc<Vec<PathBuf>>>>,
+    allowed_extensions: Arc<RwLock<Arc<HashSet<String>>>>,
     artwork_path: &'static str,
     root_listing: RootListing,
 }
@@ -82,6 +87,15 @@ pub struct LocalAudioPlayer {
     info_sender: watch::Sender<SpotifyPlayerInfo>,
     info_receiver: watch::Receiver<SpotifyPlayerInfo>,
     state: Arc<Mutex<LocalPlaybackState>>,
+    seek_request: Arc<Mutex<Option<SeekRequest>>>,
+    track_sleep: Arc<TrackSleepTimer>,
+    play_order: Arc<PlayOrder>,
+}
+
+/// A position to continue from, for the file it was requested for
+struct SeekRequest {
+    entry_id: String,
+    position: Duration,
 }
 
 pub struct LocalPlaybackQueue {
@@ -91,28 +105,38 @@ pub struct LocalPlaybackQueue {
 
 #[derive(Default)]
 struct LocalPlaybackState {
+    /// The files in the order they play, shuffled or not
     queue: Vec<LocalAudioEntry>,
+    /// The files in their original order, to return to when shuffle is turned off
+    unshuffled: Vec<LocalAudioEntry>,
     current_index: usize,
+    /// Whether the queue follows the repeat mode at its end. Otherwise it stops there, e.g. to
+    /// continue with the next item of the system queue.
+    repeat: bool,
+    /// The sleep timer paused playback after the last file of the queue
+    paused_at_end: bool,
     cancel: Option<Arc<AtomicBool>>,
 }
 
 impl LocalAudioLibrary {
     pub fn new(settings: &LocalAudioSettings) -> Self {
-        let roots = settings.roots.iter().map(PathBuf::from).collect::<Vec<_>>();
-        let allowed_extensions = settings
-            .allowed_extensions
-            .iter()
-            .map(|ext| ext.trim_start_matches('.').to_lowercase())
-            .collect::<HashSet<_>>();
+        let (roots, allowed_extensions) = library_paths(settings);
 
         Self {
-            roots: Arc::new(roots),
-            allowed_extensions: Arc::new(allowed_extensions),
+            roots: Arc::new(RwLock::new(roots)),
+            allowed_extensions: Arc::new(RwLock::new(allowed_extensions)),
             artwork_path: "/library/local/artwork",
             root_listing: RootListing::Albums,
         }
     }
 
+    /// Switch to new roots and extensions, this applies to every clone of the library
+    pub fn update_settings(&self, settings: &LocalAudioSettings) {
+        let (roots, allowed_extensions) = library_paths(settings);
+        *self.roots.write().unwrap() = roots;
+        *self.allowed_extensions.write().unwrap() = allowed_extensions;
+    }
+
     pub fn new_youtube(settings: &LocalAudioSettings) -> Self {
         let mut library = Self::new(settings);
         library.artwork_path = "/library/youtube/artwork";
@@ -121,7 +145,7 @@ impl LocalAudioLibrary {
     }
 
     pub fn roots(&self) -> Vec<LocalAudioEntry> {
-        self.roots
+        self.root_paths()
             .iter()
             .enumerate()
             .map(|(idx, root)| {
@@ -187,7 +211,7 @@ impl LocalAudioLibrary {
 
     fn list_albums(&self) -> Result<Vec<LocalAudioEntry>> {
         let mut entries = Vec::new();
-        for root in self.roots.iter() {
+        for root in self.root_paths().iter() {
             self.collect_album_entries(root, true, &mut entries)?;
         }
 
@@ -255,6 +279,29 @@ impl LocalAudioLibrary {
         Ok(path)
     }
 
+    /// The reference of an audio file given by its absolute path, or by a path relative to one of
+    /// the roots
+    pub fn find_file_ref(&self, path: &Path) -> Result<String> {
+        let file = if path.is_absolute() {
+            Some(path.to_path_buf())
+        } else {
+            self.root_paths()
+                .iter()
+                .map(|root| root.join(path))
+                .find(|candidate| candidate.is_file())
+        }
+        .filter(|file| file.is_file())
+        .ok_or_else(|| anyhow!("Local audio file does not exist: {}", path.display()))?;
+
+        if !self.is_audio_file(&file) {
+            anyhow::bail!(
+                "Local file is not an allowed audio type: {}",
+                file.display()
+            );
+        }
+        self.path_to_ref(&file)
+    }
+
     pub fn resolve_to_files(&self, reference: &str) -> Result<Vec<LocalAudioEntry>> {
         let path = self.resolve_ref(reference)?;
         if path.is_file() {
@@ -382,8 +429,8 @@ impl LocalAudioLibrary {
 
     fn resolve_path_ref(&self, reference: &str) -> Result<PathBuf> {
         let (root_idx, relative) = parse_local_ref(reference)?;
-        let root = self
-            .roots
+        let roots = self.root_paths();
+        let root = roots
             .get(root_idx)
             .ok_or_else(|| anyhow!("Unknown local audio root: {root_idx}"))?;
         let root = root.canonicalize().unwrap_or_else(|_| root.clone());
@@ -403,7 +450,7 @@ impl LocalAudioLibrary {
 
     fn path_to_ref(&self, path: &Path) -> Result<String> {
         let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-        for (idx, root) in self.roots.iter().enumerate() {
+        for (idx, root) in self.root_paths().iter().enumerate() {
             let root = root.canonicalize().unwrap_or_else(|_| root.clone());
             if canonical.starts_with(&root) {
                 let relative = canonical
@@ -424,10 +471,19 @@ impl LocalAudioLibrary {
         )
     }
 
+    fn root_paths(&self) -> Arc<Vec<PathBuf>> {
+        self.roots.read().unwrap().clone()
+    }
+
     fn is_audio_file(&self, path: &Path) -> bool {
         path.extension()
             .and_then(|ext| ext.to_str())
-            .map(|ext| self.allowed_extensions.contains(&ext.to_lowercase()))
+            .map(|ext| {
+                self.allowed_extensions
+                    .read()
+                    .unwrap()
+                    .contains(&ext.to_lowercase())
+            })
             .unwrap_or(false)
     }
 
@@ -450,7 +506,11 @@ impl LocalAudioLibrary {
 }
 
 impl LocalAudioPlayer {
-    pub fn new(audio_sender: SyncSender<SinkEvent>) -> Self {
+    pub fn new(
+        audio_sender: SyncSender<SinkEvent>,
+        track_sleep: Arc<TrackSleepTimer>,
+        play_order: Arc<PlayOrder>,
+    ) -> Self {
         let info = SpotifyPlayerInfo::stopped();
         let (info_sender, info_receiver) = watch::channel(info);
         Self {
@@ -458,6 +518,9 @@ impl LocalAudioPlayer {
             info_sender,
             info_receiver,
             state: Arc::new(Mutex::new(LocalPlaybackState::default())),
+            seek_request: Arc::new(Mutex::new(None)),
+            track_sleep,
+            play_order,
         }
     }
 
@@ -477,8 +540,8 @@ impl LocalAudioPlayer {
 
     pub fn play_queue_with_repeat(
         &self,
-        queue: Vec<LocalAudioEntry>,
-        start_index: usize,
+        mut queue: Vec<LocalAudioEntry>,
+        mut start_index: usize,
         library: LocalAudioLibrary,
         source: impl Into<String>,
         repeat: bool,
@@ -487,44 +550,115 @@ impl LocalAudioPlayer {
             self.stop();
             return Ok(());
         }
+        self.clear_seek_request();
+        self.state.lock().unwrap().unshuffled = queue.clone();
+        if self.play_order.shuffle() {
+            shuffle_from(&mut queue, start_index);
+            start_index = 0;
+        }
         self.start_from(queue, start_index, library, source.into(), repeat);
         Ok(())
     }
 
+    /// Shuffle the rest of the queue or return to its original order, the current file keeps
+    /// playing
+    pub fn set_shuffle(&self, shuffle: bool) {
+        let mut state = self.state.lock().unwrap();
+        let Some(current) = state.queue.get(state.current_index).cloned() else {
+            return;
+        };
+
+        if shuffle {
+            let current_index = state.current_index;
+            shuffle_from(&mut state.queue, current_index);
+            state.current_index = 0;
+        } else if !state.unshuffled.is_empty() {
+            state.queue = state.unshuffled.clone();
+            state.current_index = state
+                .queue
+                .iter()
+                .position(|entry| entry.id == current.id)
+                .unwrap_or(0);
+        }
+    }
+
     pub fn play(&self, library: LocalAudioLibrary, source: impl Into<String>) {
-        let (queue, index) = {
-            let state = self.state.lock().unwrap();
-            (state.queue.clone(), state.current_index)
+        let (queue, index, repeat, paused_at_end) = {
+            let mut state = self.state.lock().unwrap();
+            (
+                state.queue.clone(),
+                state.current_index,
+                state.repeat,
+                std::mem::take(&mut state.paused_at_end),
+            )
         };
-        if !queue.is_empty() {
-            self.start_from(queue, index, library, source.into(), true);
+        if paused_at_end {
+            // Resuming continues with whatever comes after this queue
+            self.stop();
+        } else if !queue.is_empty() {
+            self.start_from(queue, index, library, source.into(), repeat);
         }
     }
 
     pub fn pause(&self) {
         self.cancel_current();
         let mut info = self.info_receiver.borrow().clone();
+        // Playback of the file restarts on resume, continue where we left off
+        if let Some(position_ms) = info.position_ms {
+            self.seek(Duration::from_millis(position_ms));
+        }
         info.status = SpotifyPlayerState::Paused;
         let _ = self.info_sender.send(info);
     }
 
     pub fn stop(&self) {
         self.cancel_current();
+        self.clear_seek_request();
         let _ = self.info_sender.send(SpotifyPlayerInfo::stopped());
     }
 
     pub fn next(&self, library: LocalAudioLibrary, source: impl Into<String>) {
-        let (queue, current_index) = {
+        self.skip(library, source.into(), |index, len| (index + 1) % len);
+    }
+
+    pub fn previous(&self, library: LocalAudioLibrary, source: impl Into<String>) {
+        self.skip(library, source.into(), |index, len| (index + len - 1) % len);
+    }
+
+    /// Seek within the current file. When paused, playback resumes from this position.
+    pub fn seek(&self, position: Duration) {
+        let state = self.state.lock().unwrap();
+        let Some(entry) = state.queue.get(state.current_index) else {
+            return;
+        };
+        *self.seek_request.lock().unwrap() = Some(SeekRequest {
+            entry_id: entry.id.clone(),
+            position,
+        });
+    }
+
+    fn skip(
+        &self,
+        library: LocalAudioLibrary,
+        source: String,
+        target_index: impl FnOnce(usize, usize) -> usize,
+    ) {
+        let (queue, current_index, repeat) = {
             let state = self.state.lock().unwrap();
-            (state.queue.clone(), state.current_index)
+            (state.queue.clone(), state.current_index, state.repeat)
         };
         if queue.is_empty() {
             self.stop();
             return;
         }
 
-        let next_index = (current_index + 1) % queue.len();
-        self.start_from(queue, next_index, library, source.into(), true);
+        self.clear_seek_request();
+        let index = target_index(current_index, queue.len());
+        self.start_from(queue, index, library, source, repeat);
+    }
+
+    fn clear_seek_request(&self) {
+        *self.seek_request.lock().unwrap() = None;
     }
 
     fn start_from(
@@ -547,15 +681,19 @@ impl LocalAudioPlayer {
             let mut state = self.state.lock().unwrap();
             state.queue = queue.clone();
             state.current_index = index;
+            state.repeat = repeat;
+            state.paused_at_end = false;
             state.cancel = Some(cancel.clone());
         }
 
         let audio_sender = self.audio_sender.clone();
         let info_sender = self.info_sender.clone();
         let state = self.state.clone();
+        let seek_request = self.seek_request.clone();
+        let track_sleep = self.track_sleep.clone();
+        let play_order = self.play_order.clone();
 
         thread::spawn(move || {
-            let mut current_index = index;
             let mut consecutive_failures = 0usize;
 
             loop {
@@ -563,62 +701,141 @@ impl LocalAudioPlayer {
                     return;
                 }
 
-                {
-                    let mut state = state.lock().unwrap();
-                    state.current_index = current_index;
-                }
+                // The queue is read on every file, shuffling reorders it while it plays
+                let (entry, index, queue_len) = {
+                    let state = state.lock().unwrap();
+                    match state.queue.get(state.current_index) {
+                        Some(entry) => (entry.clone(), state.current_index, state.queue.len()),
+                        None => break,
+                    }
+                };
 
-                let entry = queue[current_index].clone();
-                let path = match library.resolve_ref(&entry.path) {
-                    Ok(path) => path,
+                let played_to_end = match library.resolve_ref(&entry.path) {
+                    Ok(path) => {
+                        let artist = entry
+                            .metadata
+                            .as_ref()
+                            .and_then(|metadata| metadata.display_artist())
+                            .unwrap_or_else(|| "Local files".to_string());
+                        let artwork_url = library.image_uri(&path).unwrap_or_default();
+
+                        let _ = info_sender.send(SpotifyPlayerInfo {
+                            status: SpotifyPlayerState::Playing,
+                            shuffle: play_order.shuffle(),
+                            repeat: play_order.repeat(),
+                            metadata: Some(MusicMetadata {
+                                artist,
+                                title: entry.name.clone(),
+                                artwork_url,
+                                source: Some(source.clone()),
+                                album: entry
+                                    .metadata
+                                    .as_ref()
+                                    .and_then(|metadata| metadata.album.clone()),
+                                duration_ms: None,
+                            }),
+                            position_ms: Some(0),
+                            sleep_timer: None,
+                            sleep_timer_mode: None,
+                            volume: None,
+                            monitor_alarm: false,
+                            monitor_disconnected: false,
+                            has_previous: index > 0,
+                        });
+                        let report_progress = |position: Duration, duration: Option<Duration>| {
+                            if cancel.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            info_sender.send_modify(|info| {
+                                info.position_ms = Some(position.as_millis() as u64);
+                                if let Some(metadata) = info.metadata.as_mut() {
+                                    metadata.duration_ms = duration.map(|d| d.as_millis() as u64);
+                                }
+                            });
+                        };
+
+                        let volume = playback_volume_for_source(&source);
+                        match play_file_blocking(
+                            &path,
+                            audio_sender.clone(),
+                            cancel.clone(),
+                            volume,
+                            &entry.id,
+                            &seek_request,
+                            &report_progress,
+                        ) {
+                            Ok(()) => {
+                                consecutive_failures = 0;
+                                !cancel.load(Ordering::Relaxed)
+                            }
+                            Err(e) => {
+                                log::warn!(
+                                    "Failed to play local audio file {}: {e}",
+                                    path.display()
+                                );
+                                consecutive_failures += 1;
+                                false
+                            }
+                        }
+                    }
                     Err(e) => {
                         log::warn!("Skipping local audio item {}: {e}", entry.path);
-                        continue;
+                        consecutive_failures += 1;
+                        false
                     }
                 };
 
-                let artist = entry
-                    .metadata
-                    .as_ref()
-                    .and_then(|metadata| metadata.display_artist())
-                    .unwrap_or_else(|| "Local files".to_string());
-                let artwork_url = library.image_uri(&path).unwrap_or_default();
-
-                let _ = info_sender.send(SpotifyPlayerInfo {
-                    status: SpotifyPlayerState::Playing,
-                    shuffle: false,
-                    metadata: Some(MusicMetadata {
-                        artist,
-                        title: entry.name.clone(),
-                        artwork_url,
-                        source: Some(source.clone()),
-                    }),
-                    sleep_timer: None,
-                });
-
-                let volume = playback_volume_for_source(&source);
-                if let Err(e) =
-                    play_file_blocking(&path, audio_sender.clone(), cancel.clone(), volume)
-                {
-                    log::warn!("Failed to play local audio file {}: {e}", path.display());
-                    consecutive_failures += 1;
-                } else {
-                    consecutive_failures = 0;
+                if cancel.load(Ordering::Relaxed) {
+                    return;
                 }
-
-                if consecutive_failures >= queue.len() {
+                if consecutive_failures >= queue_len {
                     log::warn!("Stopping local playback after every queued file failed");
                     break;
                 }
 
-                if current_index + 1 >= queue.len() {
-                    if repeat {
-                        current_index = 0;
-                    } else {
-                        break;
+                let mut state_guard = state.lock().unwrap();
+                let current_index = state_guard.current_index;
+                let queue_len = state_guard.queue.len();
+                let queue_ends = current_index + 1 >= queue_len;
+                let repeat_mode = play_order.repeat();
+                let sleep = played_to_end
+                    && (track_sleep.track_ended()
+                        || (queue_ends
+                            && repeat
+                            && repeat_mode != RepeatMode::One
+                            && track_sleep.queue_ended()));
+
+                let next_index = if played_to_end && repeat_mode == RepeatMode::One {
+                    Some(current_index)
+                } else if !queue_ends {
+                    Some(current_index + 1)
+                } else if repeat && repeat_mode != RepeatMode::Off {
+                    if play_order.shuffle() {
+                        state_guard.queue.shuffle(&mut rand::thread_rng());
                     }
+                    Some(0)
                 } else {
-                    current_index += 1;
+                    None
+                };
+
+                let Some(next_index) = next_index else {
+                    if sleep {
+                        state_guard.paused_at_end = true;
+                        drop(state_guard);
+                        info_sender.send_modify(|info| info.status = SpotifyPlayerState::Paused);
+                        return;
+                    }
+                    break;
+                };
+                state_guard.current_index = next_index;
+                drop(state_guard);
+
+                if sleep {
+                    info_sender.send_modify(|info| {
+                        info.status = SpotifyPlayerState::Paused;
+                        info.position_ms = Some(0);
+                    });
+                    return;
                 }
             }
 
@@ -644,6 +861,9 @@ fn play_file_blocking(
     audio_sender: SyncSender<SinkEvent>,
     cancel: Arc<AtomicBool>,
     volume: f64,
+    entry_id: &str,
+    seek_request: &Mutex<Option<SeekRequest>>,
+    report_progress: &dyn Fn(Duration, Option<Duration>),
 ) -> Result<()> {
     let uri = gst::glib::filename_to_uri(path, None)
         .map_err(|_| anyhow!("Failed to build file URI for {}", path.display()))?;
@@ -670,13 +890,40 @@ fn play_file_blocking(
 
     audio_sender.send(SinkEvent::Start)?;
     pipeline.set_state(gst::State::Playing)?;
+    let mut last_progress: Option<std::time::Instant> = None;
 
     loop {
         if cancel.load(Ordering::Relaxed) {
             break;
         }
 
+        if last_progress.is_none_or(|last| last.elapsed() >= PROGRESS_UPDATE_INTERVAL)
+            && let Some(position) = pipeline.query_position::<gst::ClockTime>()
+        {
+            let duration = pipeline.query_duration::<gst::ClockTime>();
+            report_progress(position.into(), duration.map(Duration::from));
+            last_progress = Some(std::time::Instant::now());
+        }
+
         if let Some(sample) = appsink.try_pull_sample(gst::ClockTime::from_mseconds(100)) {
+            // Seeking is only possible once the pipeline is running, i.e. produces samples
+            // A request for another file, e.g. made while the previous one came to its end, is dropped
+            let position = seek_request
+                .lock()
+                .unwrap()
+                .take()
+                .filter(|request| request.entry_id == entry_id)
+                .map(|request| request.position);
+            if let Some(position) = position {
+                let position = gst::ClockTime::from_nseconds(position.as_nanos() as u64);
+                if let Err(e) =
+                    pipeline.seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE, position)
+                {
+                    log::warn!("Failed to seek local audio file {}: {e}", path.display());
+                }
+                continue;
+            }
+
             let buffer = sample
                 .buffer()
                 .ok_or_else(|| anyhow!("Local audio sample has no buffer"))?;
@@ -716,6 +963,16 @@ fn playback_volume_for_source(source: &str) -> f64 {
     }
 }
 
+fn library_paths(settings: &LocalAudioSettings) -> (Arc<Vec<PathBuf>>, Arc<HashSet<String>>) {
+    let roots = settings.roots.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let allowed_extensions = settings
+        .allowed_extensions
+        .iter()
+        .map(|ext| ext.trim_start_matches('.').to_lowercase())
+        .collect::<HashSet<_>>();
+    (Arc::new(roots), Arc::new(allowed_extensions))
+}
+
 fn parse_local_ref(reference: &str) -> Result<(usize, String)> {
     let reference = reference
         .strip_prefix("local:file:")
@@ -1,14 +1,25 @@
+mod api_auth;
 mod app_settings;
+mod atomic_file;
 mod data_paths;
+mod ducking;
 mod local_audio;
+mod metrics;
+mod monitor_watchdog;
 mod music_timer;
 mod pipeline;
+mod play_order;
 mod playback_controller;
+mod playlist_formats;
+mod routines;
 mod server;
+mod settings_reload;
+mod sound_detector;
 mod spotify_client;
 mod spotify_player;
 mod spotify_sink;
 mod system_playlists;
+mod volume_control;
 mod webserver;
 
 use crate::app_settings::ApplicationSettings;
@@ -22,7 +33,7 @@ async fn main() -> Result<(), Error> {
 
     let settings = ApplicationSettings::load().context("Failed to load settings")?;
     let mut server = CareChordsServer::new(&settings);
-    let rtsp_server = crate::pipeline::rtsp_server::RtspServer::new(settings.rtsp_port)?;
+    let rtsp_server = crate::pipeline::rtsp_server::RtspServer::new(&settings)?;
 
     tokio::spawn(async move {
         if let Err(e) = rtsp_server.start().await {
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Times the GStreamer pipeline was restarted after it stopped or failed
+pub static PIPELINE_RESTARTS: Counter = Counter::new();
+/// Times the GStreamer pipeline was replaced by one built from new settings
+pub static PIPELINE_REPLACEMENTS: Counter = Counter::new();
+/// Times the GStreamer pipeline could not be set to playing
+pub static PIPELINE_START_FAILURES: Counter = Counter::new();
+/// Music buffers the `AudioBridge` failed to push into the pipeline
+pub static BUFFER_PUSH_FAILURES: Counter = Counter::new();
+/// Attempts to reconnect an invalid Spotify session
+pub static SPOTIFY_RECONNECTS: Counter = Counter::new();
+/// Spotify tracks that failed to load
+pub static TRACK_LOAD_FAILURES: Counter = Counter::new();
+/// Clients currently connected to the RTSP server
+pub static RTSP_CLIENTS: Gauge = Gauge::new();
+
+static HTTP_REQUESTS: Mutex<BTreeMap<(String, u16), Histogram>> = Mutex::new(BTreeMap::new());
+
+/// Upper bounds (in seconds) of the HTTP request duration buckets
+const HTTP_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+pub struct Counter(AtomicU64);
+
+pub struct Gauge(AtomicI64);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; HTTP_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub fn record_http_request(method: &str, status: u16, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let mut requests = HTTP_REQUESTS.lock().unwrap();
+    let histogram = requests.entry((method.to_string(), status)).or_default();
+    for (bucket, upper_bound) in histogram.buckets.iter_mut().zip(HTTP_BUCKETS) {
+        if seconds <= upper_bound {
+            *bucket += 1;
+        }
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+/// Writes metrics in the Prometheus text exposition format
+#[derive(Default)]
+pub struct MetricsWriter {
+    output: String,
+}
+
+impl MetricsWriter {
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.output, "{name} {value}");
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.output, "{name} {}", format_value(value));
+    }
+
+    /// A gauge with one sample per label value
+    pub fn labeled_gauge(&mut self, name: &str, help: &str, label: &str, values: &[(&str, f64)]) {
+        self.header(name, help, "gauge");
+        for (label_value, value) in values {
+            let _ = writeln!(
+                self.output,
+                "{name}{{{label}=\"{}\"}} {}",
+                escape_label(label_value),
+                format_value(*value)
+            );
+        }
+    }
+
+    /// The counters and histograms tracked in this module
+    pub fn recorded(&mut self) {
+        self.counter(
+            "carechords_pipeline_restarts_total",
+            "Times the audio pipeline was restarted",
+            PIPELINE_RESTARTS.get(),
+        );
+        self.counter(
+            "carechords_pipeline_replacements_total",
+            "Times the audio pipeline was replaced after the settings changed",
+            PIPELINE_REPLACEMENTS.get(),
+        );
+        self.counter(
+            "carechords_pipeline_start_failures_total",
+            "Times the audio pipeline could not be started",
+            PIPELINE_START_FAILURES.get(),
+        );
+        self.counter(
+            "carechords_buffer_push_failures_total",
+            "Music buffers that could not be pushed into the pipeline",
+            BUFFER_PUSH_FAILURES.get(),
+        );
+        self.counter(
+            "carechords_spotify_reconnects_total",
+            "Attempts to reconnect the Spotify session",
+            SPOTIFY_RECONNECTS.get(),
+        );
+        self.counter(
+            "carechords_track_load_failures_total",
+            "Spotify tracks that failed to load",
+            TRACK_LOAD_FAILURES.get(),
+        );
+        self.gauge(
+            "carechords_rtsp_clients",
+            "Clients connected to the RTSP server",
+            RTSP_CLIENTS.get() as f64,
+        );
+
+        let name = "carechords_http_request_duration_seconds";
+        self.header(name, "Duration of HTTP requests", "histogram");
+        for ((method, status), histogram) in HTTP_REQUESTS.lock().unwrap().iter() {
+            let labels = format!("method=\"{}\",status=\"{status}\"", escape_label(method));
+            for (upper_bound, count) in HTTP_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    self.output,
+                    "{name}_bucket{{{labels},le=\"{upper_bound}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                self.output,
+                "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(self.output, "{name}_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(self.output, "{name}_count{{{labels}}} {}", histogram.count);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.output
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.output, "# HELP {name} {help}");
+        let _ = writeln!(self.output, "# TYPE {name} {kind}");
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_requests_are_written_as_histograms() {
+        record_http_request("GET", 200, Duration::from_millis(20));
+        record_http_request("GET", 200, Duration::from_secs(3));
+
+        let mut writer = MetricsWriter::default();
+        writer.recorded();
+        let output = writer.finish();
+
+        let name = "carechords_http_request_duration_seconds";
+        assert!(output.contains(&format!("# TYPE {name} histogram")));
+        assert!(output.contains(&format!(
+            "{name}_bucket{{method=\"GET\",status=\"200\",le=\"0.025\"}} 1"
+        )));
+        assert!(output.contains(&format!(
+            "{name}_bucket{{method=\"GET\",status=\"200\",le=\"+Inf\"}} 2"
+        )));
+        assert!(output.contains(&format!("{name}_count{{method=\"GET\",status=\"200\"}} 2")));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::app_settings::MonitorWatchdogSettings;
+use crate::pipeline::audio_pipeline::PipelineHandle;
+use crate::sound_detector::MonitorLoudness;
+use gstreamer::prelude::ObjectExt;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, watch};
+use tokio::time::{Instant, MissedTickBehavior, interval};
+
+const WATCHDOG_TICK: Duration = Duration::from_millis(100);
+
+/// The chime sounds for CHIME_ON out of every CHIME_PERIOD
+const CHIME_PERIOD: Duration = Duration::from_secs(2);
+const CHIME_ON: Duration = Duration::from_millis(300);
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MonitorAlarmKind {
+    Raised,
+    Cleared,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MonitorAlarmEvent {
+    pub kind: MonitorAlarmKind,
+    /// Unix epoch (milliseconds) of the last time the monitor was heard
+    pub last_heard_at: u64,
+    pub silent_for_ms: u64,
+}
+
+/// Raises an alarm when the baby monitor can no longer be heard.
+///
+/// A live camera always carries some background noise, so a monitor that stays below the silence
+/// level, or stops reporting its loudness at all, for longer than the timeout has lost its camera.
+/// While the alarm is raised a chime is mixed into the stream, the alarm flag is set and an event
+/// is broadcast, until the monitor can be heard again. The cameras are measured before their gain
+/// and mute, so muting a camera does not raise the alarm.
+pub struct MonitorWatchdog {
+    settings: MonitorWatchdogSettings,
+    pipeline: Arc<PipelineHandle>,
+    alarm_sender: watch::Sender<bool>,
+    event_sender: broadcast::Sender<MonitorAlarmEvent>,
+}
+
+impl MonitorWatchdog {
+    pub fn new(settings: &MonitorWatchdogSettings, pipeline: Arc<PipelineHandle>) -> Self {
+        let (alarm_sender, _) = watch::channel(false);
+        let (event_sender, _) = broadcast::channel(16);
+
+        Self {
+            settings: settings.clone(),
+            pipeline,
+            alarm_sender,
+            event_sender,
+        }
+    }
+
+    /// This channel emits whether the alarm is raised
+    pub fn alarm_channel(&self) -> watch::Receiver<bool> {
+        self.alarm_sender.subscribe()
+    }
+
+    pub fn alarm_active(&self) -> bool {
+        *self.alarm_sender.borrow()
+    }
+
+    /// This channel emits an event when the alarm is raised or cleared
+    pub fn subscribe(&self) -> broadcast::Receiver<MonitorAlarmEvent> {
+        self.event_sender.subscribe()
+    }
+
+    pub fn start(self: &Arc<Self>, mut loudness: watch::Receiver<MonitorLoudness>) {
+        if !self.settings.enabled {
+            log::info!("Monitor watchdog is disabled");
+            return;
+        }
+        let watchdog = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = interval(WATCHDOG_TICK);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            let timeout = Duration::from_secs(watchdog.settings.timeout_s);
+            // Give the cameras the timeout to connect after startup
+            let mut last_heard = (Instant::now(), SystemTime::now());
+            let mut alarm_since: Option<Instant> = None;
+
+            loop {
+                ticker.tick().await;
+                let now = Instant::now();
+
+                // Only fresh measurements count, the pipeline stops reporting when it is down
+                if loudness.has_changed().unwrap_or(false)
+                    && loudness.borrow_and_update().peak_db > watchdog.settings.silence_db
+                {
+                    last_heard = (now, SystemTime::now());
+                }
+                let silent_for = now.duration_since(last_heard.0);
+
+                match alarm_since {
+                    None if silent_for >= timeout => {
+                        log::error!(
+                            "Monitor has been silent for {}s, raising alarm",
+                            silent_for.as_secs()
+                        );
+                        alarm_since = Some(now);
+                        watchdog.notify(MonitorAlarmKind::Raised, last_heard.1, silent_for);
+                    }
+                    Some(_) if silent_for < timeout => {
+                        log::info!("Monitor can be heard again, clearing alarm");
+                        alarm_since = None;
+                        watchdog.notify(MonitorAlarmKind::Cleared, last_heard.1, silent_for);
+                        watchdog.set_chime(false);
+                    }
+                    Some(since) if watchdog.settings.chime => {
+                        let phase =
+                            now.duration_since(since).as_millis() % CHIME_PERIOD.as_millis();
+                        watchdog.set_chime(phase < CHIME_ON.as_millis());
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    fn notify(&self, kind: MonitorAlarmKind, last_heard: SystemTime, silent_for: Duration) {
+        self.alarm_sender
+            .send_replace(kind == MonitorAlarmKind::Raised);
+        let _ = self.event_sender.send(MonitorAlarmEvent {
+            kind,
+            last_heard_at: last_heard
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            silent_for_ms: silent_for.as_millis() as u64,
+        });
+    }
+
+    fn set_chime(&self, audible: bool) {
+        let pad = self.pipeline.current().chime_mixer_pad.clone();
+        if pad.property::<bool>("mute") == audible {
+            pad.set_property("mute", !audible);
+        }
+    }
+}
@@ -1,9 +1,57 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::FRAC_PI_2;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::Mutex as TokioMutex;
 use tokio::task::JoinHandle;
 use tokio::time::{Instant, sleep};
 
+/// Interval between two volume changes of a fade
+const FADE_STEP: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    /// Changes the loudness evenly, the volume changes slowly at first and fast at the end of a
+    /// fade in
+    Logarithmic,
+    /// Keeps the perceived power constant when crossing over, a gentle start of a fade in
+    EqualPower,
+}
+
+impl FadeCurve {
+    /// The gain of a fade in after the given fraction (0.0 - 1.0) of the fade
+    pub fn gain(self, progress: f64) -> f64 {
+        let progress = progress.clamp(0.0, 1.0);
+        if progress == 0.0 {
+            return 0.0;
+        }
+        match self {
+            FadeCurve::Linear => progress,
+            // From -60 dB to 0 dB
+            FadeCurve::Logarithmic => 10f64.powf(3.0 * (progress - 1.0)),
+            FadeCurve::EqualPower => (progress * FRAC_PI_2).sin(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fade {
+    pub duration: Duration,
+    pub curve: FadeCurve,
+}
+
+impl Default for Fade {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(10),
+            curve: FadeCurve::Linear,
+        }
+    }
+}
+
 pub struct MusicVolume {
     volume: Mutex<f64>,
 }
@@ -48,7 +96,8 @@ impl SleepTimer {
         }
     }
 
-    pub async fn set_timer<F, Fut>(&self, delay: Duration, on_elapsed: F)
+    /// Fade out and call on_elapsed after the delay. The fade ends when the timer elapses.
+    pub async fn set_timer<F, Fut>(&self, delay: Duration, fade: Fade, on_elapsed: F)
     where
         F: FnOnce() -> Fut + Send + 'static,
         Fut: std::future::Future<Output = ()> + Send + 'static,
@@ -69,9 +118,13 @@ impl SleepTimer {
 
         let volume = self.volume.clone();
         let initial_volume = inner.initial_volume;
+        let fade = Fade {
+            duration: fade.duration.min(delay),
+            ..fade
+        };
         inner.handle = Some(tokio::spawn(async move {
-            sleep(delay).await;
-            fade_out_volume(volume.clone(), initial_volume).await;
+            sleep(delay - fade.duration).await;
+            fade_out_volume(volume.clone(), initial_volume, fade).await;
             on_elapsed().await;
 
             sleep(Duration::from_secs(1)).await;
@@ -91,15 +144,192 @@ impl SleepTimer {
     }
 }
 
-async fn fade_out_volume(volume: Arc<MusicVolume>, initial_volume: f64) {
+/// How a running sleep timer ends, reported next to the remaining seconds of a timed one
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum SleepTimerMode {
+    Time,
+    /// Pause once this many more tracks have played to their end
+    Tracks {
+        remaining_tracks: u32,
+    },
+    /// Pause at the end of the system queue or playlist instead of starting over
+    EndOfQueue,
+}
+
+/// A sleep timer that pauses at the end of a track instead of after a delay. The players ask it
+/// whether to pause whenever a track plays to its end.
+#[derive(Default)]
+pub struct TrackSleepTimer {
+    mode: Mutex<Option<SleepTimerMode>>,
+}
+
+impl TrackSleepTimer {
+    /// Set a `Tracks` or `EndOfQueue` timer, or clear it with `None`
+    pub fn set(&self, mode: Option<SleepTimerMode>) {
+        *self.mode.lock().unwrap() = mode.filter(|mode| *mode != SleepTimerMode::Time);
+    }
+
+    pub fn mode(&self) -> Option<SleepTimerMode> {
+        *self.mode.lock().unwrap()
+    }
+
+    /// A track played to its end, returns whether to pause before the next one
+    pub fn track_ended(&self) -> bool {
+        let mut mode = self.mode.lock().unwrap();
+        match mode.as_mut() {
+            Some(SleepTimerMode::Tracks { remaining_tracks }) if *remaining_tracks > 1 => {
+                *remaining_tracks -= 1;
+                false
+            }
+            Some(SleepTimerMode::Tracks { .. }) => {
+                log::info!("Sleep timer elapsed at the end of the track");
+                *mode = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The queue or a repeating playlist played to its end, returns whether to pause instead of
+    /// starting over
+    pub fn queue_ended(&self) -> bool {
+        let mut mode = self.mode.lock().unwrap();
+        if *mode == Some(SleepTimerMode::EndOfQueue) {
+            log::info!("Sleep timer elapsed at the end of the queue");
+            *mode = None;
+            return true;
+        }
+        false
+    }
+}
+
+/// Fades the music in when playback starts. It has a gain of its own, the sleep timer fades out
+/// another one, so neither of them undoes the other.
+pub struct MusicFader {
+    volume: Arc<MusicVolume>,
+    fade_in: Fade,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl MusicFader {
+    /// Playback starts at full volume when the fade in duration is zero
+    pub fn new(volume: Arc<MusicVolume>, fade_in: Fade) -> Self {
+        Self {
+            volume,
+            fade_in,
+            handle: Mutex::new(None),
+        }
+    }
+
+    /// Fade in with the configured fade, if any
+    pub fn playback_started(&self) {
+        if !self.fade_in.duration.is_zero() {
+            self.fade_in(self.fade_in.duration);
+        }
+    }
+
+    /// Fade in over the given duration with the configured curve, replacing a running fade
+    pub fn fade_in(&self, duration: Duration) {
+        let fade = Fade {
+            duration,
+            curve: self.fade_in.curve,
+        };
+        self.volume.set_volume(0.0);
+        let handle = tokio::spawn(fade_in_volume(self.volume.clone(), fade));
+        if let Some(previous) = self.handle.lock().unwrap().replace(handle) {
+            previous.abort();
+        }
+    }
+
+    /// Keep the music silent until the next fade in, so nothing is heard before it starts
+    pub fn hold(&self) {
+        self.stop_fade();
+        self.volume.set_volume(0.0);
+    }
+
+    /// Stop a running fade or hold at full volume
+    pub fn release(&self) {
+        self.stop_fade();
+        self.volume.set_volume(1.0);
+    }
+
+    fn stop_fade(&self) {
+        if let Some(previous) = self.handle.lock().unwrap().take() {
+            previous.abort();
+        }
+    }
+}
+
+async fn fade_out_volume(volume: Arc<MusicVolume>, initial_volume: f64, fade: Fade) {
     log::trace!("Fading out music volume");
-    let fade_duration = Duration::from_secs(10);
-    let steps = 100;
-    let step_duration = fade_duration / steps;
+    let steps = fade_steps(fade.duration);
+    let step_duration = fade.duration / steps;
 
     for step in 0..steps {
         let fraction = (step + 1) as f64 / steps as f64;
-        volume.set_volume(initial_volume * (1.0 - fraction));
+        volume.set_volume(initial_volume * fade.curve.gain(1.0 - fraction));
         sleep(step_duration).await;
     }
 }
+
+async fn fade_in_volume(volume: Arc<MusicVolume>, fade: Fade) {
+    log::trace!("Fading in music volume");
+    let steps = fade_steps(fade.duration);
+    let step_duration = fade.duration / steps;
+
+    volume.set_volume(0.0);
+    for step in 0..steps {
+        sleep(step_duration).await;
+        let fraction = (step + 1) as f64 / steps as f64;
+        volume.set_volume(fade.curve.gain(fraction));
+    }
+}
+
+fn fade_steps(duration: Duration) -> u32 {
+    (duration.as_millis() / FADE_STEP.as_millis()).clamp(1, u32::MAX as u128) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fade_curves_start_silent_and_end_at_full_volume() {
+        for curve in [
+            FadeCurve::Linear,
+            FadeCurve::Logarithmic,
+            FadeCurve::EqualPower,
+        ] {
+            assert_eq!(curve.gain(0.0), 0.0);
+            assert!((curve.gain(1.0) - 1.0).abs() < 1e-9);
+            assert!(curve.gain(0.25) < curve.gain(0.5));
+        }
+        assert!(FadeCurve::Logarithmic.gain(0.5) < FadeCurve::Linear.gain(0.5));
+        assert!(FadeCurve::EqualPower.gain(0.5) > FadeCurve::Linear.gain(0.5));
+    }
+
+    #[test]
+    fn track_sleep_timer_pauses_after_the_last_counted_track() {
+        let timer = TrackSleepTimer::default();
+        timer.set(Some(SleepTimerMode::Tracks {
+            remaining_tracks: 2,
+        }));
+        assert!(!timer.queue_ended());
+        assert!(!timer.track_ended());
+        assert_eq!(
+            timer.mode(),
+            Some(SleepTimerMode::Tracks {
+                remaining_tracks: 1
+            })
+        );
+        assert!(timer.track_ended());
+        assert_eq!(timer.mode(), None);
+        assert!(!timer.track_ended());
+
+        timer.set(Some(SleepTimerMode::EndOfQueue));
+        assert!(!timer.track_ended());
+        assert!(timer.queue_ended());
+        assert_eq!(timer.mode(), None);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::metrics;
 use crate::music_timer::MusicVolume;
 use crate::spotify_sink::SinkEvent;
 use gstreamer::{Buffer, ClockTime};
@@ -12,7 +13,8 @@ pub struct AudioBridge {
 }
 
 impl AudioBridge {
-    pub fn new(receiver: Receiver<SinkEvent>, volume: Arc<MusicVolume>) -> Self {
+    /// Pushes the music samples to the pipeline, scaled by the product of `gains`
+    pub fn new(receiver: Receiver<SinkEvent>, gains: Vec<Arc<MusicVolume>>) -> Self {
         let app_src: Arc<Mutex<Option<AppSrc>>> = Arc::new(Mutex::new(None));
         let app_src_clone = app_src.clone();
 
@@ -25,7 +27,7 @@ impl AudioBridge {
                         if samples.is_empty() {
                             continue;
                         }
-                        let volume = volume.get_volume();
+                        let volume = gains.iter().map(|gain| gain.get_volume()).product::<f64>();
                         let samples = if volume < 1.0 {
                             samples
                                 .into_iter()
@@ -69,6 +71,7 @@ impl AudioBridge {
 
                             if let Err(err) = src.push_buffer(buffer) {
                                 log::warn!("Failed to push buffer to AppSrc: {:?}", err);
+                                metrics::BUFFER_PUSH_FAILURES.inc();
                                 // If pushing fails, we assume the pipeline is dead or dying.
                                 // We don't break the loop, we just wait for a new AppSrc to be set.
                                 // But we might want to clear the current one to avoid spamming errors?
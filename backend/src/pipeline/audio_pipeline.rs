@@ -1,18 +1,42 @@
-use crate::app_settings::ApplicationSettings;
-use crate::pipeline::monitor_source::MonitorSourcePipeline;
+use crate::app_settings::{ApplicationSettings, RtspMountKind};
+use crate::pipeline::monitor_source::{MonitorMix, MonitorSourcePipeline};
 use crate::pipeline::spotify_source::SpotifySourcePipeline;
 use anyhow::Error;
-use gstreamer::prelude::{ElementExt, ElementExtManual, GstBinExtManual, ObjectExt, PipelineExt};
+use gstreamer::prelude::{
+    ElementExt, ElementExtManual, GObjectExtManualGst, GstBinExtManual, GstObjectExt, ObjectExt,
+    PadExt, PipelineExt,
+};
 use gstreamer::{
-    Bus, Caps, ClockTime, Element, ElementFactory, Pipeline, State, StateChangeSuccess, init,
+    Bus, Caps, ClockTime, Element, ElementFactory, Pad, Pipeline, State, StateChangeSuccess,
+    Structure, init, message,
 };
+use std::sync::{Arc, Mutex};
+
+/// The local UDP ports the pipeline sends each signal to, the RTSP server receives them there
+pub fn stream_udp_port(kind: RtspMountKind) -> i32 {
+    match kind {
+        RtspMountKind::Mixed => 5000,
+        RtspMountKind::Monitor => 5001,
+        RtspMountKind::Music => 5002,
+    }
+}
 
 #[allow(dead_code)]
 pub struct AudioPipeline {
     pub gstreamer_pipeline: Pipeline,
-    pub monitor: MonitorSourcePipeline,
+    pub monitors: Vec<MonitorSourcePipeline>,
+    pub monitor_mix: MonitorMix,
     pub spotify: SpotifySourcePipeline,
     pub elements: AudioPipelineElements,
+    /// The audio mixer pads the branches are linked to, used to control their volume
+    pub monitor_mixer_pad: Pad,
+    pub spotify_mixer_pad: Pad,
+    /// Muted unless the monitor watchdog raised its alarm
+    pub chime_mixer_pad: Pad,
+    /// The monitor mixer pad of each monitor, by monitor name
+    pub monitor_pads: Vec<(String, Pad)>,
+    pub monitor_tap: Option<BranchTap>,
+    pub spotify_tap: Option<BranchTap>,
 }
 
 pub struct AudioPipelineElements {
@@ -24,6 +48,34 @@ pub struct AudioPipelineElements {
     udp_sink: Element,
 }
 
+/// A tone mixed into the stream while the monitor watchdog alarm is raised
+pub struct AlarmChime {
+    source: Element,
+    cap_filter: Element,
+}
+
+/// Splits a branch before the audio mixer so it is also sent to the RTSP server on its own
+pub struct BranchTap {
+    tee: Element,
+    mixer_queue: Element,
+    tap_queue: Element,
+    convert: Element,
+    resample: Element,
+    stereo_filter: Element,
+    rtp_pay: Element,
+    udp_sink: Element,
+}
+
+/// Name of the application message that asks the bus loop to stop the pipeline so it can be
+/// replaced, see `PipelineHandle::replace`
+pub const REPLACE_PIPELINE_MESSAGE: &str = "carechords-replace-pipeline";
+
+/// Holds the pipeline the GStreamer loop runs, so it can be replaced by a pipeline built from new
+/// settings
+pub struct PipelineHandle {
+    current: Mutex<Arc<AudioPipeline>>,
+}
+
 pub trait PipeLineBranch {
     fn add_to_pipeline(&self, pipeline: &Pipeline) -> Result<(), Error>;
     fn link_elements(&self) -> Result<(), Error>;
@@ -36,35 +88,86 @@ impl AudioPipeline {
 
         let pipeline = Pipeline::new();
 
-        let monitor = MonitorSourcePipeline::new(&settings.monitor_url, settings.noise_filter)?;
+        let monitors = settings
+            .monitors
+            .iter()
+            .enumerate()
+            .map(|(index, monitor)| {
+                MonitorSourcePipeline::new(
+                    index,
+                    &monitor.url,
+                    monitor.noise_filter.unwrap_or(settings.noise_filter),
+                )
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let monitor_mix = MonitorMix::new()?;
         let spotify = SpotifySourcePipeline::new()?;
+        let chime = AlarmChime::new()?;
         let common = AudioPipelineElements::new()?;
 
-        monitor.add_to_pipeline(&pipeline)?;
+        for monitor in &monitors {
+            monitor.add_to_pipeline(&pipeline)?;
+        }
+        monitor_mix.add_to_pipeline(&pipeline)?;
         common.add_to_pipeline(&pipeline)?;
         spotify.add_to_pipeline(&pipeline)?;
+        chime.add_to_pipeline(&pipeline)?;
 
-        monitor.link_elements()?;
+        for monitor in &monitors {
+            monitor.link_elements()?;
+        }
+        monitor_mix.link_elements()?;
         spotify.link_elements()?;
+        chime.link_elements()?;
         common.link_elements()?;
 
-        monitor
-            .last_element()
-            .link(&common.audio_mixer)
-            .expect("Failed to link livestream to audio mixer");
+        let monitor_pads = settings
+            .monitors
+            .iter()
+            .zip(&monitors)
+            .map(|(settings, monitor)| {
+                Ok((settings.name.clone(), monitor_mix.link_monitor(monitor)?))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let stream_settings = &settings.rtsp_stream;
+        let monitor_tap = stream_settings
+            .mount_enabled(RtspMountKind::Monitor)
+            .then(|| BranchTap::new("monitor", stream_udp_port(RtspMountKind::Monitor)))
+            .transpose()?;
+        let spotify_tap = stream_settings
+            .mount_enabled(RtspMountKind::Music)
+            .then(|| BranchTap::new("music", stream_udp_port(RtspMountKind::Music)))
+            .transpose()?;
 
-        spotify
-            .last_element()
-            .link(&common.audio_mixer)
-            .expect("Failed to link audio mixer");
+        let monitor_output = match &monitor_tap {
+            Some(tap) => tap.insert_after(&pipeline, &monitor_mix.last_element())?,
+            None => monitor_mix.last_element(),
+        };
+        let spotify_output = match &spotify_tap {
+            Some(tap) => tap.insert_after(&pipeline, &spotify.last_element())?,
+            None => spotify.last_element(),
+        };
+
+        let monitor_mixer_pad = common.link_to_mixer(&monitor_output)?;
+        let spotify_mixer_pad = common.link_to_mixer(&spotify_output)?;
+        let chime_mixer_pad = common.link_to_mixer(&chime.last_element())?;
+        chime_mixer_pad.set_property("mute", true);
 
         pipeline.set_latency(ClockTime::from_mseconds(1000));
 
         Ok(Self {
             gstreamer_pipeline: pipeline,
-            monitor,
+            monitors,
+            monitor_mix,
             spotify,
             elements: common,
+            monitor_mixer_pad,
+            spotify_mixer_pad,
+            chime_mixer_pad,
+            monitor_pads,
+            monitor_tap,
+            spotify_tap,
         })
     }
 
@@ -76,6 +179,32 @@ impl AudioPipeline {
     pub fn get_bus(&self) -> Option<Bus> {
         self.gstreamer_pipeline.bus()
     }
+
+    /// Ask the bus loop to stop this pipeline
+    fn request_replace(&self) {
+        let message = message::Application::new(Structure::new_empty(REPLACE_PIPELINE_MESSAGE));
+        if self.gstreamer_pipeline.post_message(message).is_err() {
+            log::warn!("Failed to post pipeline replace message");
+        }
+    }
+}
+
+impl PipelineHandle {
+    pub fn new(pipeline: AudioPipeline) -> Self {
+        Self {
+            current: Mutex::new(Arc::new(pipeline)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<AudioPipeline> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Swap in a new pipeline and stop the running one, the GStreamer loop then starts the new one
+    pub fn replace(&self, pipeline: AudioPipeline) {
+        let previous = std::mem::replace(&mut *self.current.lock().unwrap(), Arc::new(pipeline));
+        previous.request_replace();
+    }
 }
 
 impl AudioPipelineElements {
@@ -102,7 +231,7 @@ impl AudioPipelineElements {
         queue.set_property("max-size-time", &200_000_000u64); // 200ms to bound latency for AudioMixer
 
         udp_sink.set_property("host", "127.0.0.1");
-        udp_sink.set_property("port", &5000);
+        udp_sink.set_property("port", stream_udp_port(RtspMountKind::Mixed));
 
         // Set explicit latency on the mixer to avoid latency negotiation issues
         // with unbounded sinks (udpsink reports max_latency=0)
@@ -126,6 +255,19 @@ impl AudioPipelineElements {
         })
     }
 
+    /// Link the output of a branch to a new sink pad on the audio mixer
+    fn link_to_mixer(&self, element: &Element) -> Result<Pad, Error> {
+        let mixer_pad = self
+            .audio_mixer
+            .request_pad_simple("sink_%u")
+            .ok_or_else(|| anyhow::anyhow!("Could not request audio mixer sink pad"))?;
+        let src_pad = element
+            .static_pad("src")
+            .ok_or_else(|| anyhow::anyhow!("{} has no src pad", element.name()))?;
+        src_pad.link(&mixer_pad)?;
+        Ok(mixer_pad)
+    }
+
     fn add_to_pipeline(&self, pipeline: &Pipeline) -> Result<(), Error> {
         pipeline.add_many(&[
             &self.audio_mixer,
@@ -150,3 +292,116 @@ impl AudioPipelineElements {
         Ok(())
     }
 }
+
+impl AlarmChime {
+    fn new() -> Result<Self, Error> {
+        let source = ElementFactory::make_with_name("audiotestsrc", Some("AlarmChime"))
+            .map_err(|_| anyhow::anyhow!("Could not create alarm_chime element."))?;
+        let cap_filter = ElementFactory::make_with_name("capsfilter", Some("AlarmChimeCaps"))
+            .map_err(|_| anyhow::anyhow!("Could not create alarm_chime_caps element."))?;
+
+        source.set_property_from_str("wave", "sine");
+        source.set_property("freq", 880.0f64);
+        source.set_property("volume", 0.4f64);
+        source.set_property("is-live", true);
+        cap_filter.set_property(
+            "caps",
+            &Caps::builder("audio/x-raw")
+                .field("format", "F32LE")
+                .field("rate", 44100)
+                .field("channels", 2)
+                .build(),
+        );
+
+        Ok(Self { source, cap_filter })
+    }
+}
+
+impl PipeLineBranch for AlarmChime {
+    fn add_to_pipeline(&self, pipeline: &Pipeline) -> Result<(), Error> {
+        pipeline.add_many([&self.source, &self.cap_filter])?;
+        Ok(())
+    }
+
+    fn link_elements(&self) -> Result<(), Error> {
+        Element::link_many([&self.source, &self.cap_filter])?;
+        Ok(())
+    }
+
+    fn last_element(&self) -> Element {
+        self.cap_filter.clone()
+    }
+}
+
+impl BranchTap {
+    fn new(name: &str, port: i32) -> Result<Self, Error> {
+        let make = |factory: &str, element: &str| {
+            ElementFactory::make_with_name(factory, Some(&format!("{name}_tap_{element}")))
+                .map_err(|_| anyhow::anyhow!("Could not create {name}_tap_{element} element."))
+        };
+
+        let tee = make("tee", "tee")?;
+        let mixer_queue = make("queue", "mixer_queue")?;
+        let tap_queue = make("queue", "queue")?;
+        let convert = make("audioconvert", "convert")?;
+        let resample = make("audioresample", "resample")?;
+        let stereo_filter = make("capsfilter", "stereo_filter")?;
+        let rtp_pay = make("rtpL16pay", "rtp_pay")?;
+        let udp_sink = make("udpsink", "udp_sink")?;
+
+        // A slow or missing RTSP client must never hold up the mixer
+        tap_queue.set_property_from_str("leaky", "downstream");
+        tap_queue.set_property("max-size-time", 200_000_000u64);
+
+        stereo_filter.set_property(
+            "caps",
+            Caps::builder("audio/x-raw")
+                .field("channels", 2)
+                .field("rate", 44100)
+                .build(),
+        );
+
+        udp_sink.set_property("host", "127.0.0.1");
+        udp_sink.set_property("port", port);
+        udp_sink.set_property("async", false);
+
+        Ok(Self {
+            tee,
+            mixer_queue,
+            tap_queue,
+            convert,
+            resample,
+            stereo_filter,
+            rtp_pay,
+            udp_sink,
+        })
+    }
+
+    /// Add the tap to the pipeline behind the given element, returns the element that should be
+    /// linked to the audio mixer instead
+    fn insert_after(&self, pipeline: &Pipeline, element: &Element) -> Result<Element, Error> {
+        pipeline.add_many([
+            &self.tee,
+            &self.mixer_queue,
+            &self.tap_queue,
+            &self.convert,
+            &self.resample,
+            &self.stereo_filter,
+            &self.rtp_pay,
+            &self.udp_sink,
+        ])?;
+
+        element.link(&self.tee)?;
+        Element::link_many([&self.tee, &self.mixer_queue])?;
+        Element::link_many([
+            &self.tee,
+            &self.tap_queue,
+            &self.convert,
+            &self.resample,
+            &self.stereo_filter,
+            &self.rtp_pay,
+            &self.udp_sink,
+        ])?;
+        Ok(self.mixer_queue.clone())
+    }
+}
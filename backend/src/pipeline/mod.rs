@@ -1,6 +1,8 @@
 pub mod audio_bridge;
 pub mod audio_pipeline;
+pub mod monitor_recovery;
 pub mod monitor_source;
+pub mod pipeline_health;
 pub mod rtsp_server;
 pub mod spotify_source;
 
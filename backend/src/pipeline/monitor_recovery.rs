@@ -0,0 +1,290 @@
+use crate::pipeline::AudioPipeline;
+use crate::pipeline::audio_pipeline::PipelineHandle;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Delay before the first reconnect attempt, doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a reconnecting camera gets to deliver audio before the attempt is left to the RTSP
+/// source timeouts
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum MonitorConnection {
+    Connecting,
+    Connected,
+    Disconnected { retry_in_ms: u64 },
+}
+
+/// Recovers the camera branches of the pipeline without interrupting the music.
+///
+/// An error in a camera branch stops only that branch, the monitor mixer keeps running on its
+/// silent input. The branch is restarted after an exponential backoff, which reconnects the
+/// camera, until it delivers audio again.
+pub struct MonitorRecovery {
+    pipeline: Arc<PipelineHandle>,
+    monitors: Mutex<HashMap<String, RecoveryState>>,
+    disconnected_sender: watch::Sender<bool>,
+}
+
+struct RecoveryState {
+    /// Failed connection attempts since the camera was last connected
+    attempts: u32,
+    /// Identifies the scheduled reconnect, so a stale one does not touch a newer attempt
+    generation: u64,
+    phase: RecoveryPhase,
+}
+
+enum RecoveryPhase {
+    Waiting { retry_at: Instant },
+    Connecting,
+}
+
+impl MonitorRecovery {
+    pub fn new(pipeline: Arc<PipelineHandle>) -> Self {
+        let (disconnected_sender, _) = watch::channel(false);
+
+        Self {
+            pipeline,
+            monitors: Mutex::new(HashMap::new()),
+            disconnected_sender,
+        }
+    }
+
+    /// This channel emits whether any camera is being reconnected
+    pub fn disconnected_channel(&self) -> watch::Receiver<bool> {
+        self.disconnected_sender.subscribe()
+    }
+
+    /// Forget all attempts, for a pipeline that was (re)started as a whole
+    pub fn reset(&self) {
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.clear();
+        self.publish(&monitors);
+    }
+
+    /// Handle an error posted by the element at this path. Returns false if it did not come from
+    /// a camera branch, in which case the pipeline has to be restarted.
+    pub fn handle_error(self: &Arc<Self>, source: &str) -> bool {
+        let Some(index) = monitor_index(source) else {
+            return false;
+        };
+        let pipeline = self.pipeline.current();
+        let (Some(monitor), Some((name, _))) = (
+            pipeline.monitors.get(index),
+            pipeline.monitor_pads.get(index),
+        ) else {
+            return false;
+        };
+
+        let (delay, generation) = {
+            let mut monitors = self.monitors.lock().unwrap();
+            let state = monitors.entry(name.clone()).or_insert(RecoveryState {
+                attempts: 0,
+                generation: 0,
+                phase: RecoveryPhase::Connecting,
+            });
+            // A failing branch usually posts several errors, only the first one counts
+            if matches!(state.phase, RecoveryPhase::Waiting { .. }) {
+                return true;
+            }
+            let next_attempt = back_off(state);
+            self.publish(&monitors);
+            next_attempt
+        };
+
+        log::warn!(
+            "Monitor {} disconnected, reconnecting in {}s",
+            name,
+            delay.as_secs()
+        );
+        monitor.stop();
+        self.schedule_reconnect(pipeline.clone(), index, name.clone(), delay, generation);
+        true
+    }
+
+    fn schedule_reconnect(
+        self: &Arc<Self>,
+        pipeline: Arc<AudioPipeline>,
+        index: usize,
+        name: String,
+        delay: Duration,
+        generation: u64,
+    ) {
+        let recovery = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            recovery.reconnect(pipeline, index, name, generation).await;
+        });
+    }
+
+    async fn reconnect(
+        self: Arc<Self>,
+        pipeline: Arc<AudioPipeline>,
+        index: usize,
+        name: String,
+        generation: u64,
+    ) {
+        // The pipeline was replaced or restarted in the meantime
+        if !Arc::ptr_eq(&pipeline, &self.pipeline.current())
+            || !self.set_connecting(&name, generation)
+        {
+            return;
+        }
+
+        log::info!("Reconnecting monitor {}", name);
+        let monitor = &pipeline.monitors[index];
+        match monitor.restart() {
+            Ok(()) => {
+                let started = Instant::now();
+                while started.elapsed() < CONNECT_TIMEOUT {
+                    tokio::time::sleep(CONNECT_POLL_INTERVAL).await;
+                    if !self.is_current(&name, generation) {
+                        return;
+                    }
+                    if monitor.is_connected() {
+                        log::info!("Monitor {} reconnected", name);
+                        let mut monitors = self.monitors.lock().unwrap();
+                        monitors.remove(&name);
+                        self.publish(&monitors);
+                        return;
+                    }
+                }
+            }
+            Err(e) => log::error!("Failed to restart monitor {}: {}", name, e),
+        }
+
+        // Neither connected nor failed with an error of its own, so nothing else retries it
+        let Some((delay, generation)) = self.retry(&name, generation) else {
+            return;
+        };
+        log::warn!(
+            "Monitor {} did not reconnect, retrying in {}s",
+            name,
+            delay.as_secs()
+        );
+        monitor.stop();
+        self.schedule_reconnect(pipeline.clone(), index, name, delay, generation);
+    }
+
+    /// Back off for the next attempt, unless a newer one took over in the meantime
+    fn retry(&self, name: &str, generation: u64) -> Option<(Duration, u64)> {
+        let mut monitors = self.monitors.lock().unwrap();
+        match monitors.get_mut(name) {
+            Some(state) if state.generation == generation => Some(back_off(state)),
+            _ => None,
+        }
+    }
+
+    fn publish(&self, monitors: &HashMap<String, RecoveryState>) {
+        let disconnected = !monitors.is_empty();
+        self.disconnected_sender.send_if_modified(|current| {
+            let changed = *current != disconnected;
+            *current = disconnected;
+            changed
+        });
+    }
+
+    fn set_connecting(&self, name: &str, generation: u64) -> bool {
+        let mut monitors = self.monitors.lock().unwrap();
+        match monitors.get_mut(name) {
+            Some(state) if state.generation == generation => {
+                state.phase = RecoveryPhase::Connecting;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn is_current(&self, name: &str, generation: u64) -> bool {
+        self.monitors
+            .lock()
+            .unwrap()
+            .get(name)
+            .is_some_and(|state| state.generation == generation)
+    }
+
+    /// The name of the camera whose branch the element at this path belongs to
+    pub fn monitor_name(&self, source: &str) -> Option<String> {
+        let index = monitor_index(source)?;
+        let pipeline = self.pipeline.current();
+        pipeline
+            .monitor_pads
+            .get(index)
+            .map(|(name, _)| name.clone())
+    }
+
+    /// The connection state of every camera of the running pipeline, by name
+    pub fn connections(&self) -> Vec<(String, MonitorConnection)> {
+        let pipeline = self.pipeline.current();
+        let monitors = self.monitors.lock().unwrap();
+        pipeline
+            .monitor_pads
+            .iter()
+            .zip(&pipeline.monitors)
+            .map(|((name, _), monitor)| {
+                let connection = match monitors.get(name).map(|state| &state.phase) {
+                    Some(RecoveryPhase::Waiting { retry_at }) => MonitorConnection::Disconnected {
+                        retry_in_ms: retry_at
+                            .saturating_duration_since(Instant::now())
+                            .as_millis() as u64,
+                    },
+                    Some(RecoveryPhase::Connecting) => MonitorConnection::Connecting,
+                    None if monitor.is_connected() => MonitorConnection::Connected,
+                    None => MonitorConnection::Connecting,
+                };
+                (name.clone(), connection)
+            })
+            .collect()
+    }
+}
+
+/// Wait twice as long as before for the next attempt. Returns the delay and the generation of the
+/// attempt.
+fn back_off(state: &mut RecoveryState) -> (Duration, u64) {
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(state.attempts))
+        .min(MAX_BACKOFF);
+    state.attempts += 1;
+    state.generation += 1;
+    state.phase = RecoveryPhase::Waiting {
+        retry_at: Instant::now() + delay,
+    };
+    (delay, state.generation)
+}
+
+/// The index of the camera branch an element belongs to, taken from the element path
+/// (e.g. `/GstPipeline:pipeline0/GstRTSPSrc:livestream1_source`)
+fn monitor_index(source: &str) -> Option<usize> {
+    let (_, rest) = source.split_once(":livestream")?;
+    let digits = rest.split('_').next()?;
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_attributed_to_the_camera_branch() {
+        assert_eq!(
+            monitor_index("/GstPipeline:pipeline0/GstRTSPSrc:livestream1_source/GstUDPSrc:udpsrc0"),
+            Some(1)
+        );
+        assert_eq!(
+            monitor_index("/GstPipeline:pipeline0/GstDecodeBin:livestream12_decoder"),
+            Some(12)
+        );
+        assert_eq!(
+            monitor_index("/GstPipeline:pipeline0/GstAudioMixer:AudioMixer"),
+            None
+        );
+    }
+}
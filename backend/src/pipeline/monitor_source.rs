@@ -1,9 +1,10 @@
 use crate::pipeline::audio_pipeline::PipeLineBranch;
-use anyhow::Error;
+use anyhow::{Context, Error};
 use gstreamer::prelude::{
-    ElementExt, GObjectExtManualGst, GstBinExt, GstBinExtManual, ObjectExt, PadExt,
+    ElementExt, ElementExtManual, GObjectExtManualGst, GstBinExt, GstBinExtManual, GstObjectExt,
+    ObjectExt, PadExt,
 };
-use gstreamer::{Caps, Element, ElementFactory, Pipeline};
+use gstreamer::{Caps, Element, ElementFactory, Pad, Pipeline, State};
 use gstreamer_rtsp::RTSPLowerTrans;
 use log::error;
 
@@ -19,36 +20,38 @@ pub struct MonitorSourcePipeline {
     post_mix_resample: Element,
     dsp: Option<Element>,
     pub cap_filter: Element,
+    level: Element,
+}
+
+/// Mixes the audio of all monitors before it enters the main audio mixer, so every camera can
+/// have its own gain and mute state while the monitor volume applies to all of them
+pub struct MonitorMix {
+    silence: Element,
+    silence_caps: Element,
+    mixer: Element,
+    convert: Element,
 }
 
 impl MonitorSourcePipeline {
-    pub fn new(rtsp_url: &str, noise_filter: bool) -> Result<Self, Error> {
-        let source = ElementFactory::make_with_name("rtspsrc", Some("livestream_source"))
-            .expect("Could not create livestream_source element.");
-        let depay = ElementFactory::make_with_name("rtpmp4gdepay", Some("livestream_depay"))
-            .expect("Could not create livestream_depay element.");
-        let parse = ElementFactory::make_with_name("aacparse", Some("livestream_parse"))
-            .expect("Could not create livestream_parse element.");
-        let decoder = ElementFactory::make_with_name("decodebin", Some("livestream_decoder"))
-            .expect("Could not create livestream_decoder element.");
-        let resample_mixer =
-            ElementFactory::make_with_name("audiomixer", Some("livestream_resample_mixer"))
-                .expect("Could not create livestream_queue element.");
-        let pre_mix_queue = ElementFactory::make_with_name("queue2", Some("livestream_queue"))
-            .expect("Could not create livestream_queue element.");
-        let pre_mix_convert =
-            ElementFactory::make_with_name("audioconvert", Some("livestream_convert"))
-                .expect("Could not create livestream_convert element.");
-        let post_mix_convert =
-            ElementFactory::make_with_name("audioconvert", Some("livestream_convert2"))
-                .expect("Could not create livestream_convert element.");
-        let post_mix_resample =
-            ElementFactory::make_with_name("audioresample", Some("livestream_resample"))
-                .expect("Could not create livestream_resample element.");
-
-        let cap_filter =
-            ElementFactory::make_with_name("capsfilter", Some("livestream_cap_filter"))
-                .expect("Failed to create capsfilter");
+    /// Create the branch of a monitor, the index keeps the element names of each monitor unique
+    pub fn new(index: usize, rtsp_url: &str, noise_filter: bool) -> Result<Self, Error> {
+        let make = |factory: &str, element: &str| {
+            let name = format!("livestream{index}_{element}");
+            ElementFactory::make_with_name(factory, Some(&name))
+                .with_context(|| format!("Could not create {name} element."))
+        };
+
+        let source = make("rtspsrc", "source")?;
+        let depay = make("rtpmp4gdepay", "depay")?;
+        let parse = make("aacparse", "parse")?;
+        let decoder = make("decodebin", "decoder")?;
+        let resample_mixer = make("audiomixer", "resample_mixer")?;
+        let pre_mix_queue = make("queue2", "queue")?;
+        let pre_mix_convert = make("audioconvert", "convert")?;
+        let post_mix_convert = make("audioconvert", "convert2")?;
+        let post_mix_resample = make("audioresample", "resample")?;
+        let cap_filter = make("capsfilter", "cap_filter")?;
+        let level = make("level", "level")?;
 
         // Set properties
         source.set_property("location", rtsp_url);
@@ -57,7 +60,7 @@ impl MonitorSourcePipeline {
 
         let dsp = noise_filter
             .then(|| {
-                ElementFactory::make_with_name("webrtcdsp", Some("livestream_dsp"))
+                ElementFactory::make_with_name("webrtcdsp", Some(&format!("livestream{index}_dsp")))
                     .inspect_err(|_| log::warn!("Failed to create noise filter dsp (webrtcdsp from gst-bad-plugins), forcing noise_filter option to false."))
                     .ok()
                     .map(|dsp| {
@@ -80,6 +83,10 @@ impl MonitorSourcePipeline {
                 .build(),
         );
 
+        // Post the loudness of the camera on the bus before its gain and mute, see SoundDetector
+        level.set_property("interval", 100_000_000u64); // 100ms
+        level.set_property("post-messages", true);
+
         Ok(Self {
             source,
             depay,
@@ -92,9 +99,39 @@ impl MonitorSourcePipeline {
             post_mix_resample,
             dsp,
             cap_filter,
+            level,
         })
     }
 
+    /// Stop the branch, e.g. after the camera failed, the rest of the pipeline keeps running
+    pub fn stop(&self) {
+        for element in self.elements() {
+            if let Err(e) = element.set_state(State::Null) {
+                log::warn!("Failed to stop {}: {}", element.name(), e);
+            }
+        }
+    }
+
+    /// Start a stopped branch again, which reconnects to the camera
+    pub fn restart(&self) -> Result<(), Error> {
+        // Start downstream first so the elements are ready for the data of the source
+        for element in self.elements().into_iter().rev() {
+            element.sync_state_with_parent()?;
+        }
+        Ok(())
+    }
+
+    pub fn source_state(&self) -> State {
+        self.source.current_state()
+    }
+
+    /// Whether the camera delivers audio, the source only adds its pad once the stream is set up
+    pub fn is_connected(&self) -> bool {
+        self.depay
+            .static_pad("sink")
+            .is_some_and(|pad| pad.is_linked())
+    }
+
     pub fn connect_dynamic_pads(&self) -> Result<(), Error> {
         let depay_clone = self.depay.clone();
         self.source.connect_pad_added(move |_src, src_pad| {
@@ -131,6 +168,28 @@ impl MonitorSourcePipeline {
     }
 }
 
+impl MonitorSourcePipeline {
+    fn elements(&self) -> Vec<&Element> {
+        let mut elements = vec![
+            &self.source,
+            &self.depay,
+            &self.parse,
+            &self.decoder,
+            &self.pre_mix_convert,
+            &self.pre_mix_queue,
+            &self.resample_mixer,
+            &self.post_mix_convert,
+            &self.post_mix_resample,
+            &self.cap_filter,
+            &self.level,
+        ];
+        if let Some(dsp) = &self.dsp {
+            elements.insert(7, dsp);
+        }
+        elements
+    }
+}
+
 impl PipeLineBranch for MonitorSourcePipeline {
     fn add_to_pipeline(&self, pipeline: &Pipeline) -> Result<(), Error> {
         pipeline.add_many(&[
@@ -144,6 +203,7 @@ impl PipeLineBranch for MonitorSourcePipeline {
             &self.post_mix_convert,
             &self.post_mix_resample,
             &self.cap_filter,
+            &self.level,
         ])?;
 
         if let Some(dsp) = &self.dsp {
@@ -165,6 +225,7 @@ impl PipeLineBranch for MonitorSourcePipeline {
             &self.post_mix_convert,
             &self.post_mix_resample,
             &self.cap_filter,
+            &self.level,
         ];
 
         if let Some(dsp) = &self.dsp {
@@ -177,6 +238,72 @@ impl PipeLineBranch for MonitorSourcePipeline {
     }
 
     fn last_element(&self) -> Element {
-        self.cap_filter.clone()
+        self.level.clone()
+    }
+}
+
+impl MonitorMix {
+    pub fn new() -> Result<Self, Error> {
+        // A live silent input keeps the mixer running while a camera is disconnected
+        let silence = ElementFactory::make_with_name("audiotestsrc", Some("MonitorSilence"))
+            .context("Could not create monitor_silence element.")?;
+        let silence_caps = ElementFactory::make_with_name("capsfilter", Some("MonitorSilenceCaps"))
+            .context("Could not create monitor_silence_caps element.")?;
+        let mixer = ElementFactory::make_with_name("audiomixer", Some("MonitorMixer"))
+            .context("Could not create monitor_mixer element.")?;
+        let convert = ElementFactory::make_with_name("audioconvert", Some("MonitorMixConvert"))
+            .context("Could not create monitor_mix_convert element.")?;
+
+        silence.set_property_from_str("wave", "silence");
+        silence.set_property("is-live", true);
+        silence_caps.set_property(
+            "caps",
+            &Caps::builder("audio/x-raw")
+                .field("format", "F32LE")
+                .field("rate", 44100)
+                .field("channels", 2)
+                .build(),
+        );
+
+        Ok(Self {
+            silence,
+            silence_caps,
+            mixer,
+            convert,
+        })
+    }
+
+    pub fn add_to_pipeline(&self, pipeline: &Pipeline) -> Result<(), Error> {
+        pipeline.add_many([
+            &self.silence,
+            &self.silence_caps,
+            &self.mixer,
+            &self.convert,
+        ])?;
+        Ok(())
+    }
+
+    pub fn link_elements(&self) -> Result<(), Error> {
+        Element::link_many([&self.silence, &self.silence_caps, &self.mixer])?;
+        Element::link_many([&self.mixer, &self.convert])?;
+        Ok(())
+    }
+
+    /// Link a monitor branch to a new sink pad of the monitor mixer
+    pub fn link_monitor(&self, monitor: &MonitorSourcePipeline) -> Result<Pad, Error> {
+        let mixer_pad = self
+            .mixer
+            .request_pad_simple("sink_%u")
+            .ok_or_else(|| anyhow::anyhow!("Could not request monitor mixer sink pad"))?;
+        let src_pad = monitor
+            .last_element()
+            .static_pad("src")
+            .ok_or_else(|| anyhow::anyhow!("Monitor branch has no src pad"))?;
+        src_pad.link(&mixer_pad)?;
+        Ok(mixer_pad)
+    }
+
+    pub fn last_element(&self) -> Element {
+        self.convert.clone()
     }
 }
@@ -0,0 +1,207 @@
+use crate::pipeline::audio_pipeline::PipelineHandle;
+use gstreamer::prelude::{ElementExtManual, GstBinExt, GstObjectExt, ObjectExt};
+use gstreamer::{Element, State};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Errors kept to attribute failures to the monitors
+const MAX_RECENT_ERRORS: usize = 20;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelineError {
+    /// Path of the element that posted the error
+    pub source: String,
+    pub message: String,
+    pub debug: Option<String>,
+    /// Unix epoch (milliseconds) at which the error was posted
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MonitorHealth {
+    pub name: String,
+    /// State of the RTSP source of the camera
+    pub state: String,
+    /// Whether the camera is delivering an audio stream
+    pub connected: bool,
+    pub last_error: Option<PipelineError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueHealth {
+    pub name: String,
+    pub level_time_ms: u64,
+    pub level_bytes: u32,
+    pub level_buffers: u32,
+    pub max_time_ms: u64,
+    /// How full the queue is relative to its time limit
+    pub fill_percent: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyHealth {
+    pub live: bool,
+    pub min_ms: u64,
+    pub max_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelineHealthReport {
+    pub state: String,
+    /// Unix epoch (milliseconds) at which the running pipeline was started
+    pub running_since: Option<u64>,
+    /// Times the pipeline was restarted after it stopped or failed
+    pub restarts: u32,
+    /// Times the pipeline was replaced by one built from new settings
+    pub replacements: u32,
+    /// Times the pipeline could not be set to playing
+    pub failed_starts: u32,
+    pub last_error: Option<PipelineError>,
+    pub monitors: Vec<MonitorHealth>,
+    pub queues: Vec<QueueHealth>,
+    pub latency: Option<LatencyHealth>,
+}
+
+/// Keeps track of what happened on the pipeline bus, and combines it with the live state of the
+/// pipeline elements into a health report
+pub struct PipelineHealth {
+    pipeline: Arc<PipelineHandle>,
+    state: Mutex<HealthState>,
+}
+
+#[derive(Default)]
+struct HealthState {
+    running_since: Option<u64>,
+    restarts: u32,
+    replacements: u32,
+    failed_starts: u32,
+    errors: VecDeque<PipelineError>,
+}
+
+impl PipelineHealth {
+    pub fn new(pipeline: Arc<PipelineHandle>) -> Self {
+        Self {
+            pipeline,
+            state: Mutex::new(HealthState::default()),
+        }
+    }
+
+    pub fn pipeline_started(&self) {
+        self.state.lock().unwrap().running_since = Some(epoch_millis());
+    }
+
+    /// The pipeline stopped, either on purpose to replace it or because it failed
+    pub fn pipeline_stopped(&self, replaced: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.running_since = None;
+        if replaced {
+            state.replacements += 1;
+        } else {
+            state.restarts += 1;
+        }
+    }
+
+    pub fn pipeline_start_failed(&self) {
+        self.state.lock().unwrap().failed_starts += 1;
+    }
+
+    pub fn record_error(&self, source: String, message: String, debug: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if state.errors.len() == MAX_RECENT_ERRORS {
+            state.errors.pop_front();
+        }
+        state.errors.push_back(PipelineError {
+            source,
+            message,
+            debug,
+            at: epoch_millis(),
+        });
+    }
+
+    pub fn report(&self) -> PipelineHealthReport {
+        let pipeline = self.pipeline.current();
+        let gst_pipeline = &pipeline.gstreamer_pipeline;
+        let state = self.state.lock().unwrap();
+
+        let monitors = pipeline
+            .monitor_pads
+            .iter()
+            .zip(&pipeline.monitors)
+            .enumerate()
+            .map(|(index, ((name, _), monitor))| {
+                let prefix = format!("livestream{index}_");
+                MonitorHealth {
+                    name: name.clone(),
+                    state: state_name(monitor.source_state()),
+                    connected: monitor.is_connected(),
+                    last_error: state
+                        .errors
+                        .iter()
+                        .rev()
+                        .find(|error| error.source.contains(&prefix))
+                        .cloned(),
+                }
+            })
+            .collect();
+
+        let queue_names = ["AudioMixerQueue".to_string(), "spotify_queue".to_string()]
+            .into_iter()
+            .chain((0..pipeline.monitors.len()).map(|index| format!("livestream{index}_queue")));
+        let queues = queue_names
+            .filter_map(|name| gst_pipeline.by_name(&name))
+            .map(|queue| queue_health(&queue))
+            .collect();
+
+        let mut query = gstreamer::query::Latency::new();
+        let latency = gst_pipeline.query(&mut query).then(|| {
+            let (live, min, max) = query.result();
+            LatencyHealth {
+                live,
+                min_ms: min.mseconds(),
+                max_ms: max.map(|max| max.mseconds()),
+            }
+        });
+
+        PipelineHealthReport {
+            state: state_name(gst_pipeline.current_state()),
+            running_since: state.running_since,
+            restarts: state.restarts,
+            replacements: state.replacements,
+            failed_starts: state.failed_starts,
+            last_error: state.errors.back().cloned(),
+            monitors,
+            queues,
+            latency,
+        }
+    }
+}
+
+fn queue_health(queue: &Element) -> QueueHealth {
+    let level_time = queue.property::<u64>("current-level-time");
+    let max_time = queue.property::<u64>("max-size-time");
+    QueueHealth {
+        name: queue.name().to_string(),
+        level_time_ms: level_time / 1_000_000,
+        level_bytes: queue.property::<u32>("current-level-bytes"),
+        level_buffers: queue.property::<u32>("current-level-buffers"),
+        max_time_ms: max_time / 1_000_000,
+        fill_percent: if max_time > 0 {
+            level_time as f64 / max_time as f64 * 100.0
+        } else {
+            0.0
+        },
+    }
+}
+
+fn state_name(state: State) -> String {
+    format!("{:?}", state)
+}
+
+fn epoch_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
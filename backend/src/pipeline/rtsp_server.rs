@@ -1,59 +1,129 @@
-use anyhow::Error;
+use crate::app_settings::{
+    ApplicationSettings, RtspAuthMethod, RtspCodec, RtspMountKind, RtspStreamSettings,
+};
+use crate::metrics;
+use crate::pipeline::audio_pipeline::stream_udp_port;
+use anyhow::{Context, Error};
+use gstreamer::glib;
+use gstreamer_rtsp_server::gio;
+use gstreamer_rtsp_server::gio::prelude::*;
 use gstreamer_rtsp_server::prelude::*;
-use gstreamer_rtsp_server::{RTSPMediaFactory, RTSPServer};
+use gstreamer_rtsp_server::{
+    RTSP_PERM_MEDIA_FACTORY_ACCESS, RTSP_PERM_MEDIA_FACTORY_CONSTRUCT,
+    RTSP_TOKEN_MEDIA_FACTORY_ROLE, RTSPAuth, RTSPMediaFactory, RTSPServer, RTSPToken,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// The role granted to clients that authenticated with the configured credentials
+const LISTENER_ROLE: &str = "listener";
 
 pub struct RtspServer {
     server: RTSPServer,
     port: u16,
+    /// Only clients from these networks are accepted, any client when empty
+    allowed_networks: Vec<IpNetwork>,
+}
+
+/// An address or network in CIDR notation, e.g. `10.0.0.0/24`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
 }
 
 impl RtspServer {
-    pub fn new(port: u16) -> Result<Self, Error> {
+    pub fn new(settings: &ApplicationSettings) -> Result<Self, Error> {
+        let port = settings.rtsp_port;
+        let auth_settings = &settings.rtsp_auth;
+        let stream_settings = &settings.rtsp_stream;
+
         let server = RTSPServer::new();
         server.set_service(&port.to_string());
 
-        let factory = RTSPMediaFactory::new();
-        factory.set_shared(true);
-
-        // We receive audio via UDP from the main pipeline and payload it for RTSP
-        // The udpsrc port must match the udpsink port in audio_pipeline.rs
-        let pipeline_str = "udpsrc port=5000 ! application/x-rtp,media=audio,clock-rate=44100,encoding-name=L16,channels=2,payload=96 ! rtpL16depay ! audioconvert ! avenc_aac ! rtpmp4apay name=pay0 pt=96";
-
-        factory.set_launch(pipeline_str);
-
         let mounts = server
             .mount_points()
             .ok_or_else(|| anyhow::anyhow!("Could not get mount points"))?;
 
-        factory.connect_media_configure(|_factory, media| {
-            log::info!("RTSP Media configured for stream");
-            media.connect_prepared(|_media| {
-                log::info!("RTSP Media prepared");
-            });
-            media.connect_unprepared(|_media| {
-                log::info!("RTSP Media unprepared");
-            });
-        });
+        let credentials = match (&auth_settings.username, &auth_settings.password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            _ => None,
+        };
+        if let Some((username, password)) = credentials {
+            server.set_auth(Some(&create_auth(auth_settings.method, username, password)));
+            log::info!(
+                "RTSP streams require {:?} authentication",
+                auth_settings.method
+            );
+        }
+
+        for (kind, mount_path) in stream_settings.enabled_mounts() {
+            let factory = create_factory(stream_settings, kind)?;
 
-        mounts.add_factory("/sleep", factory);
+            // Only clients carrying the listener role may open and play the stream
+            if credentials.is_some() {
+                factory.add_role_from_structure(
+                    &gstreamer::Structure::builder(LISTENER_ROLE)
+                        .field(RTSP_PERM_MEDIA_FACTORY_ACCESS, true)
+                        .field(RTSP_PERM_MEDIA_FACTORY_CONSTRUCT, true)
+                        .build(),
+                );
+            }
 
-        server.connect_client_connected(|_server, _client| {
+            mounts.add_factory(&mount_path, factory);
+            log::info!(
+                "RTSP {:?} stream mounted at {} ({:?}, {} Hz)",
+                kind,
+                mount_path,
+                stream_settings.codec,
+                stream_settings.sample_rate()
+            );
+        }
+
+        server.connect_client_connected(|_server, client| {
             log::info!("RTSP Client connected");
+            metrics::RTSP_CLIENTS.inc();
+            client.connect_closed(|_client| metrics::RTSP_CLIENTS.dec());
         });
 
-        Ok(Self { server, port })
+        Ok(Self {
+            server,
+            port,
+            allowed_networks: auth_settings.allowed_networks()?,
+        })
     }
 
     pub async fn start(&self) -> Result<(), Error> {
         let server = self.server.clone();
         let port = self.port;
+        let allowed_networks = self.allowed_networks.clone();
 
         std::thread::spawn(move || {
-            let main_loop = gstreamer::glib::MainLoop::new(None, false);
+            let main_loop = glib::MainLoop::new(None, false);
             let context = main_loop.context();
-            server
-                .attach(Some(&context))
-                .expect("Failed to attach server");
+            if allowed_networks.is_empty() {
+                server
+                    .attach(Some(&context))
+                    .expect("Failed to attach server");
+            } else {
+                // Connections are accepted here instead of by the server, so clients from other
+                // addresses are turned away before they can send a single request
+                let listener = server
+                    .create_socket(None::<&gio::Cancellable>)
+                    .expect("Failed to create the RTSP server socket");
+                SocketExtManual::create_source(
+                    &listener,
+                    glib::IOCondition::IN,
+                    None::<&gio::Cancellable>,
+                    Some("rtsp-accept"),
+                    glib::Priority::DEFAULT,
+                    move |listener, _condition| {
+                        accept_client(&server, listener, &allowed_networks);
+                        glib::ControlFlow::Continue
+                    },
+                )
+                .attach(Some(&context));
+            }
 
             log::info!("RTSP server listening on port {}", port);
 
@@ -63,3 +133,291 @@ impl RtspServer {
         Ok(())
     }
 }
+
+/// The elements that encode and payload the mixed audio for the configured codec
+fn encoder_elements(settings: &RtspStreamSettings) -> (&'static str, &'static str) {
+    match settings.codec {
+        RtspCodec::Aac => ("avenc_aac", "rtpmp4apay"),
+        RtspCodec::Opus => ("opusenc", "rtpopuspay"),
+        RtspCodec::L16 => ("", "rtpL16pay"),
+    }
+}
+
+fn create_factory(
+    settings: &RtspStreamSettings,
+    kind: RtspMountKind,
+) -> Result<RTSPMediaFactory, Error> {
+    let factory = RTSPMediaFactory::new();
+    factory.set_shared(true);
+
+    let pipeline_str = launch_string(settings, stream_udp_port(kind));
+    validate_launch(settings, &pipeline_str)?;
+    log::info!("RTSP {:?} stream pipeline: {}", kind, pipeline_str);
+
+    factory.set_launch(&pipeline_str);
+
+    factory.connect_media_configure(move |_factory, media| {
+        log::info!("RTSP Media configured for {:?} stream", kind);
+        media.connect_prepared(|_media| {
+            log::info!("RTSP Media prepared");
+        });
+        media.connect_unprepared(|_media| {
+            log::info!("RTSP Media unprepared");
+        });
+    });
+
+    Ok(factory)
+}
+
+fn launch_string(settings: &RtspStreamSettings, udp_port: i32) -> String {
+    // We receive audio via UDP from the main pipeline and payload it for RTSP
+    format!(
+        "udpsrc port={} ! application/x-rtp,media=audio,clock-rate=44100,encoding-name=L16,channels=2,payload=96 ! rtpL16depay ! {}",
+        udp_port,
+        encode_chain(settings)
+    )
+}
+
+/// Resamples, encodes and payloads the raw audio of a stream
+fn encode_chain(settings: &RtspStreamSettings) -> String {
+    // The second audioconvert gives the encoder or payloader its sample format, e.g. the big
+    // endian samples of rtpL16pay that audioresample cannot produce
+    let mut launch = format!(
+        "audioconvert ! audioresample ! audio/x-raw,rate={} ! audioconvert",
+        settings.sample_rate()
+    );
+
+    let (encoder, payloader) = encoder_elements(settings);
+    if !encoder.is_empty() {
+        launch.push_str(" ! ");
+        launch.push_str(encoder);
+        if let Some(bitrate) = settings.bitrate {
+            launch.push_str(&format!(" bitrate={}", bitrate));
+        }
+    }
+    launch.push_str(&format!(" ! {} name=pay0 pt=96", payloader));
+    launch
+}
+
+/// Make sure the stream pipeline can be built, the media factory only constructs it once the
+/// first client connects and would otherwise fail without a clear error
+fn validate_launch(settings: &RtspStreamSettings, launch: &str) -> Result<(), Error> {
+    let (encoder, payloader) = encoder_elements(settings);
+    for element in [
+        "udpsrc",
+        "rtpL16depay",
+        "audioconvert",
+        "audioresample",
+        encoder,
+        payloader,
+    ] {
+        if !element.is_empty() && gstreamer::ElementFactory::find(element).is_none() {
+            anyhow::bail!(
+                "GStreamer element {} required for the {:?} RTSP stream is not available",
+                element,
+                settings.codec
+            );
+        }
+    }
+
+    gstreamer::parse::launch(&format!("( {} )", launch))
+        .with_context(|| format!("Invalid RTSP stream pipeline: {}", launch))?;
+    check_negotiation(settings)
+}
+
+/// Let test audio flow through the encoder and payloader, parsing the pipeline alone does not
+/// show whether their formats can be negotiated
+fn check_negotiation(settings: &RtspStreamSettings) -> Result<(), Error> {
+    let launch = format!(
+        "audiotestsrc num-buffers=1 ! audio/x-raw,rate=44100,channels=2 ! {} ! fakesink",
+        encode_chain(settings)
+    );
+    let pipeline = gstreamer::parse::launch(&launch)
+        .with_context(|| format!("Invalid RTSP stream pipeline: {}", launch))?;
+
+    let _ = pipeline.set_state(gstreamer::State::Paused);
+    let (result, _, _) = pipeline.state(gstreamer::ClockTime::from_seconds(5));
+    let error = pipeline.bus().and_then(|bus| {
+        bus.pop_filtered(&[gstreamer::MessageType::Error])
+            .and_then(|message| match message.view() {
+                gstreamer::MessageView::Error(error) => Some(error.error().to_string()),
+                _ => None,
+            })
+    });
+    let _ = pipeline.set_state(gstreamer::State::Null);
+
+    if let Some(error) = error {
+        anyhow::bail!(
+            "The {:?} RTSP stream cannot be encoded: {}",
+            settings.codec,
+            error
+        );
+    }
+    if result.is_err() {
+        anyhow::bail!(
+            "The {:?} RTSP stream pipeline did not start",
+            settings.codec
+        );
+    }
+    Ok(())
+}
+
+fn create_auth(method: RtspAuthMethod, username: &str, password: &str) -> RTSPAuth {
+    let auth = RTSPAuth::new();
+    let token = RTSPToken::builder()
+        .field(RTSP_TOKEN_MEDIA_FACTORY_ROLE, LISTENER_ROLE)
+        .build();
+
+    match method {
+        RtspAuthMethod::Basic => {
+            auth.set_supported_methods(gstreamer_rtsp::RTSPAuthMethod::Basic);
+            auth.add_basic(&RTSPAuth::make_basic(username, password), &token);
+        }
+        RtspAuthMethod::Digest => {
+            auth.set_supported_methods(gstreamer_rtsp::RTSPAuthMethod::Digest);
+            auth.add_digest(username, password, &token);
+        }
+    }
+    auth
+}
+
+/// Accept a pending connection and hand it to the server when the client's address is allowed
+fn accept_client(server: &RTSPServer, listener: &gio::Socket, allowed_networks: &[IpNetwork]) {
+    let socket = match listener.accept(None::<&gio::Cancellable>) {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("Failed to accept RTSP client: {}", e);
+            return;
+        }
+    };
+    let address = socket
+        .remote_address()
+        .ok()
+        .and_then(|address| address.downcast::<gio::InetSocketAddress>().ok())
+        .map(SocketAddr::from);
+
+    match address {
+        Some(address)
+            if allowed_networks
+                .iter()
+                .any(|network| network.contains(address.ip())) =>
+        {
+            if let Err(e) = server.transfer_connection(
+                socket,
+                &address.ip().to_string(),
+                address.port() as i32,
+                None,
+            ) {
+                log::warn!("Failed to serve RTSP client from {}: {}", address, e);
+            }
+        }
+        _ => {
+            log::warn!("Rejecting RTSP client from {:?}", address);
+            let _ = socket.close();
+        }
+    }
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Clients connecting over IPv6 sockets report IPv4 addresses in their mapped form
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match value.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value.trim(), None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid address: {}", value))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| anyhow::anyhow!("Invalid network prefix: {}", value))?,
+            None => max_len,
+        };
+
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks_match_addresses_within_their_prefix() {
+        let network: IpNetwork = "10.0.0.0/24".parse().unwrap();
+        assert!(network.contains("10.0.0.51".parse().unwrap()));
+        assert!(network.contains("::ffff:10.0.0.7".parse().unwrap()));
+        assert!(!network.contains("10.0.1.1".parse().unwrap()));
+
+        let host: IpNetwork = "192.168.1.10".parse().unwrap();
+        assert!(host.contains("192.168.1.10".parse().unwrap()));
+        assert!(!host.contains("192.168.1.11".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn launch_string_follows_the_stream_settings() {
+        let opus = RtspStreamSettings {
+            codec: RtspCodec::Opus,
+            bitrate: Some(64000),
+            ..RtspStreamSettings::default()
+        };
+        assert!(launch_string(&opus, 5000).ends_with(
+            "audio/x-raw,rate=48000 ! audioconvert ! opusenc bitrate=64000 ! rtpopuspay name=pay0 pt=96"
+        ));
+
+        let l16 = RtspStreamSettings {
+            codec: RtspCodec::L16,
+            ..RtspStreamSettings::default()
+        };
+        assert!(
+            launch_string(&l16, 5000)
+                .ends_with("audio/x-raw,rate=44100 ! audioconvert ! rtpL16pay name=pay0 pt=96")
+        );
+    }
+
+    #[test]
+    fn the_l16_stream_negotiates_big_endian_samples() {
+        gstreamer::init().unwrap();
+        let l16 = RtspStreamSettings {
+            codec: RtspCodec::L16,
+            ..RtspStreamSettings::default()
+        };
+        check_negotiation(&l16).unwrap();
+    }
+}
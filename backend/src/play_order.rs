@@ -0,0 +1,131 @@
+use crate::atomic_file;
+use anyhow::{Context, Result};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RepeatMode {
+    /// Stop after the last track
+    Off,
+    /// Play the current track over and over
+    One,
+    /// Keep playing the last item of the system queue, so the music never stops. A folder or
+    /// playlist played on its own starts over.
+    #[default]
+    Last,
+    /// Start over after the last track
+    All,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct PlayOrderState {
+    #[serde(default)]
+    shuffle: bool,
+    #[serde(default)]
+    repeat: RepeatMode,
+}
+
+/// Shuffle and repeat, shared by the players and the system queue. They are persisted so they
+/// survive a restart of the service.
+pub struct PlayOrder {
+    path: PathBuf,
+    state: Mutex<PlayOrderState>,
+}
+
+impl PlayOrder {
+    pub fn new(path: PathBuf) -> Self {
+        let state = read_state(&path).unwrap_or_else(|e| {
+            log::warn!("Failed to load shuffle and repeat: {e}");
+            PlayOrderState::default()
+        });
+
+        Self {
+            path,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.state.lock().unwrap().shuffle
+    }
+
+    pub fn set_shuffle(&self, shuffle: bool) -> Result<()> {
+        self.update(|state| state.shuffle = shuffle)
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.state.lock().unwrap().repeat
+    }
+
+    pub fn set_repeat(&self, repeat: RepeatMode) -> Result<()> {
+        self.update(|state| state.repeat = repeat)
+    }
+
+    fn update(&self, change: impl FnOnce(&mut PlayOrderState)) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut updated = *state;
+        change(&mut updated);
+
+        let json = serde_json::to_vec_pretty(&updated)?;
+        atomic_file::write_with_backups(&self.path, &json, 0)?;
+        *state = updated;
+        Ok(())
+    }
+}
+
+fn read_state(path: &PathBuf) -> Result<PlayOrderState> {
+    if !path.exists() {
+        return Ok(PlayOrderState::default());
+    }
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let state = serde_json::from_slice(&bytes)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(state)
+}
+
+/// Shuffle the items, but start with the one at `first`
+pub fn shuffle_from<T>(items: &mut [T], first: usize) {
+    if items.is_empty() {
+        return;
+    }
+    items.swap(0, first.min(items.len() - 1));
+    items[1..].shuffle(&mut rand::thread_rng());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shuffles_after_the_first_item() {
+        let mut items = (0..20).collect::<Vec<_>>();
+        shuffle_from(&mut items, 7);
+
+        assert_eq!(items[0], 7);
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn keeps_shuffle_and_repeat_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("carechords-order-{}", rand::random::<u32>()));
+        let path = dir.join("play_order.json");
+
+        let play_order = PlayOrder::new(path.clone());
+        assert!(!play_order.shuffle());
+        assert_eq!(play_order.repeat(), RepeatMode::Last);
+        play_order.set_shuffle(true).unwrap();
+        play_order.set_repeat(RepeatMode::One).unwrap();
+
+        let restarted = PlayOrder::new(path);
+        assert!(restarted.shuffle());
+        assert_eq!(restarted.repeat(), RepeatMode::One);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,17 @@
 use crate::local_audio::{LocalAudioLibrary, LocalAudioPlayer};
-use crate::music_timer::{MusicVolume, SleepTimer};
+use crate::music_timer::{
+    Fade, MusicFader, MusicVolume, SleepTimer, SleepTimerMode, TrackSleepTimer,
+};
+use crate::play_order::{PlayOrder, RepeatMode};
+use crate::routines::Routine;
 use crate::spotify_client::{PlaylistSummary, SpotifyClient};
 use crate::spotify_player::{PlayerCommand, SpotifyPlayerInfo, SpotifyPlayerState};
-use crate::system_playlists::{SystemPlaylistItem, SystemPlaylistStore};
+use crate::system_playlists::{NestedPlaylistItem, SystemPlaylistItem, SystemPlaylistStore};
+use crate::volume_control::{
+    MonitorChannel, MonitorChannelRequest, VolumeControl, VolumeLevels, VolumeRequest,
+};
 use anyhow::{Result, anyhow};
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -20,6 +28,12 @@ pub struct PlaybackController {
     system_queue: Arc<Mutex<SystemQueue>>,
     active_source: Arc<Mutex<ActiveSource>>,
     sleep_timer: Arc<SleepTimer>,
+    track_sleep: Arc<TrackSleepTimer>,
+    play_order: Arc<PlayOrder>,
+    fader: Arc<MusicFader>,
+    volume: Arc<VolumeControl>,
+    monitor_alarm: watch::Receiver<bool>,
+    monitor_disconnected: watch::Receiver<bool>,
     info_sender: watch::Sender<SpotifyPlayerInfo>,
     info_receiver: watch::Receiver<SpotifyPlayerInfo>,
 }
@@ -47,16 +61,32 @@ enum ActiveSource {
 struct SystemQueue {
     items: Vec<SystemPlaylistItem>,
     current_index: usize,
-    collection_items: Vec<SystemPlaylistItem>,
+    /// The tracks of the current item when it is a system playlist
+    collection_items: Vec<NestedPlaylistItem>,
     collection_index: usize,
     collection_owner_id: Option<String>,
+    /// Ids of the items played since the queue started or last started over, shuffle picks the
+    /// next item from the others
+    played: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct SystemQueueState {
     pub items: Vec<SystemPlaylistItem>,
     pub current_index: Option<usize>,
+    /// Whether the last item keeps playing at the end of the queue, see [`RepeatMode::Last`]
     pub repeat_last: bool,
+    /// The queue with its system playlists expanded into their tracks
+    pub tracks: Vec<SystemQueueTrack>,
+    pub current_track_index: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemQueueTrack {
+    #[serde(flatten)]
+    pub track: NestedPlaylistItem,
+    /// The item in `items` the track belongs to
+    pub queue_item_id: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -95,14 +125,25 @@ pub struct LegacyPlaylistRequest {
 }
 
 impl PlaybackController {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         local_library: LocalAudioLibrary,
         youtube_library: LocalAudioLibrary,
         local_player: Arc<LocalAudioPlayer>,
         playlists: SystemPlaylistStore,
+        fader: Arc<MusicFader>,
         music_volume: Arc<MusicVolume>,
+        track_sleep: Arc<TrackSleepTimer>,
+        play_order: Arc<PlayOrder>,
+        volume: Arc<VolumeControl>,
+        monitor_alarm: watch::Receiver<bool>,
+        monitor_disconnected: watch::Receiver<bool>,
     ) -> Self {
-        let (info_sender, info_receiver) = watch::channel(SpotifyPlayerInfo::stopped());
+        let mut initial_info = SpotifyPlayerInfo::stopped();
+        initial_info.volume = Some(volume.levels());
+        initial_info.monitor_alarm = *monitor_alarm.borrow();
+        initial_info.monitor_disconnected = *monitor_disconnected.borrow();
+        let (info_sender, info_receiver) = watch::channel(initial_info);
 
         let controller = Self {
             spotify: Arc::new(Mutex::new(SpotifySlot::AuthPending)),
@@ -113,6 +154,12 @@ impl PlaybackController {
             system_queue: Arc::new(Mutex::new(SystemQueue::default())),
             active_source: Arc::new(Mutex::new(ActiveSource::None)),
             sleep_timer: Arc::new(SleepTimer::new(music_volume)),
+            track_sleep,
+            play_order,
+            fader,
+            volume,
+            monitor_alarm,
+            monitor_disconnected,
             info_sender,
             info_receiver,
         };
@@ -132,6 +179,18 @@ impl PlaybackController {
         }
 
         self.spawn_spotify_status_forwarder(runtime.client.player_info_channel());
+        // The player starts without shuffle and with the default repeat mode
+        let shuffle = self.play_order.shuffle();
+        let repeat = self.play_order.repeat();
+        let commands = runtime.commands;
+        tokio::spawn(async move {
+            if shuffle {
+                let _ = commands.send(PlayerCommand::Shuffle(true)).await;
+            }
+            if repeat != RepeatMode::default() {
+                let _ = commands.send(PlayerCommand::Repeat(repeat)).await;
+            }
+        });
     }
 
     pub fn sources(&self) -> Vec<AudioSourceStatus> {
@@ -193,7 +252,9 @@ impl PlaybackController {
         }
 
         self.clear_system_queue();
-        self.play_ref_without_system(reference).await
+        self.play_ref_without_system(reference).await?;
+        self.fader.playback_started();
+        Ok(())
     }
 
     async fn play_ref_without_system(&self, reference: &str) -> Result<()> {
@@ -245,20 +306,57 @@ impl PlaybackController {
             system_queue.collection_items.clear();
             system_queue.collection_index = 0;
             system_queue.collection_owner_id = None;
+            system_queue.played.clear();
         }
-        self.play_current_system_item().await
+        self.play_current_system_item().await?;
+        self.fader.playback_started();
+        Ok(())
     }
 
     pub fn queue_state(&self) -> SystemQueueState {
-        let system_queue = self.system_queue.lock().unwrap();
-        SystemQueueState {
-            items: system_queue.items.clone(),
-            current_index: if system_queue.items.is_empty() {
-                None
+        let (items, current_index, collection_owner_id, collection_items, collection_index) = {
+            let system_queue = self.system_queue.lock().unwrap();
+            (
+                system_queue.items.clone(),
+                (!system_queue.items.is_empty())
+                    .then(|| system_queue.current_index.min(system_queue.items.len() - 1)),
+                system_queue.collection_owner_id.clone(),
+                system_queue.collection_items.clone(),
+                system_queue.collection_index,
+            )
+        };
+
+        let mut tracks = Vec::new();
+        let mut current_track_index = None;
+        for (index, item) in items.iter().enumerate() {
+            let is_playing_collection = collection_owner_id.as_ref() == Some(&item.id);
+            if Some(index) == current_index {
+                let offset = if is_playing_collection {
+                    collection_index
+                } else {
+                    0
+                };
+                current_track_index = Some(tracks.len() + offset);
+            }
+
+            // The playing playlist may have been edited since it started, show what is played
+            let expanded = if is_playing_collection {
+                collection_items.clone()
             } else {
-                Some(system_queue.current_index.min(system_queue.items.len() - 1))
-            },
-            repeat_last: true,
+                self.expand_queue_item(item).unwrap_or_default()
+            };
+            tracks.extend(expanded.into_iter().map(|track| SystemQueueTrack {
+                track,
+                queue_item_id: item.id.clone(),
+            }));
+        }
+
+        SystemQueueState {
+            items,
+            current_index,
+            repeat_last: self.play_order.repeat() == RepeatMode::Last,
+            tracks,
+            current_track_index,
         }
     }
 
@@ -278,6 +376,7 @@ impl PlaybackController {
                 system_queue.collection_items.clear();
                 system_queue.collection_index = 0;
                 system_queue.collection_owner_id = None;
+                system_queue.played.clear();
             }
             should_start
         };
@@ -336,6 +435,7 @@ impl PlaybackController {
             system_queue.collection_items.clear();
             system_queue.collection_index = 0;
             system_queue.collection_owner_id = None;
+            system_queue.played.clear();
         }
         self.stop_active().await?;
         Ok(self.queue_state())
@@ -376,10 +476,16 @@ impl PlaybackController {
             system_queue.collection_owner_id = None;
         }
         self.play_current_system_item().await?;
+        self.fader.playback_started();
         Ok(self.queue_state())
     }
 
     pub async fn play(&self) -> Result<()> {
+        if self.active() != ActiveSource::None
+            && self.current_info().status != SpotifyPlayerState::Playing
+        {
+            self.fader.playback_started();
+        }
         match self.active() {
             ActiveSource::Spotify => self.send_spotify(PlayerCommand::Play).await,
             ActiveSource::Local => {
@@ -407,7 +513,7 @@ impl PlaybackController {
     }
 
     pub async fn next(&self) -> Result<()> {
-        if self.advance_system_queue().await? {
+        if self.advance_system_queue(false).await? {
             return Ok(());
         }
 
@@ -426,24 +532,157 @@ impl PlaybackController {
         }
     }
 
-    pub async fn sleep(&self, seconds: u32) -> Result<()> {
+    pub async fn previous(&self) -> Result<()> {
+        // Within a playlist or folder of the system queue, the player steps back to its previous
+        // track first and only then the queue to its previous item
+        if !self.current_info().has_previous && self.retreat_system_queue().await? {
+            return Ok(());
+        }
+
+        match self.active() {
+            ActiveSource::Spotify => self.send_spotify(PlayerCommand::Previous).await,
+            ActiveSource::Local => {
+                self.local_player
+                    .previous(self.local_library.clone(), "local");
+                Ok(())
+            }
+            ActiveSource::Youtube => {
+                self.local_player
+                    .previous(self.youtube_library.clone(), "youtube");
+                Ok(())
+            }
+            ActiveSource::None => Ok(()),
+        }
+    }
+
+    pub async fn seek(&self, position_ms: u32) -> Result<()> {
+        // A stopped or still loading track has no position to seek to
+        if self.current_info().status == SpotifyPlayerState::Stopped {
+            anyhow::bail!("Nothing is playing");
+        }
+        match self.active() {
+            ActiveSource::Spotify => self.send_spotify(PlayerCommand::Seek(position_ms)).await,
+            ActiveSource::Local | ActiveSource::Youtube => {
+                self.local_player
+                    .seek(Duration::from_millis(position_ms as u64));
+                Ok(())
+            }
+            ActiveSource::None => anyhow::bail!("Nothing is playing"),
+        }
+    }
+
+    pub async fn sleep(&self, seconds: u32, fade: Fade) -> Result<()> {
+        self.track_sleep.set(None);
         let controller = self.clone();
         self.sleep_timer
-            .set_timer(Duration::from_secs(seconds as u64), move || async move {
-                controller.pause_active_for_sleep_timer().await;
-            })
+            .set_timer(
+                Duration::from_secs(seconds as u64),
+                fade,
+                move || async move {
+                    controller.pause_active_for_sleep_timer().await;
+                },
+            )
             .await;
         self.emit_current_info().await;
         Ok(())
     }
 
+    /// Pause at the end of a track instead of after a delay, replacing a timed sleep timer
+    pub async fn sleep_at_end_of_track(&self, mode: SleepTimerMode) -> Result<()> {
+        if let SleepTimerMode::Tracks { remaining_tracks } = mode
+            && remaining_tracks == 0
+        {
+            anyhow::bail!("The sleep timer needs at least one track");
+        }
+        self.sleep_timer
+            .set_timer(Duration::ZERO, Fade::default(), || async {})
+            .await;
+        self.track_sleep.set(Some(mode));
+        self.emit_current_info().await;
+        Ok(())
+    }
+
+    /// Play what a routine refers to with its volume, sleep timer and fade
+    pub async fn run_routine(&self, routine: &Routine) -> Result<()> {
+        // Start silent so the music does not play at full volume before the fade in, and only
+        // touch the volume and sleep timer once the routine's music actually plays
+        if routine.fade_in_s.is_some() {
+            self.fader.hold();
+        }
+        if let Err(e) = self.play_ref(&routine.reference).await {
+            if routine.fade_in_s.is_some() {
+                self.fader.release();
+            }
+            return Err(e);
+        }
+        if let Some(seconds) = routine.fade_in_s {
+            self.fader.fade_in(Duration::from_secs(seconds as u64));
+        }
+
+        if let Some(volume) = routine.volume {
+            self.set_volume(VolumeRequest {
+                master: None,
+                music: Some(volume),
+                monitor: None,
+            })
+            .await?;
+        }
+        // The fade in has a gain of its own, it does not change the volume the sleep timer restores
+        if let Some(minutes) = routine.sleep_timer_min {
+            self.sleep(minutes.saturating_mul(60), Fade::default())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Shuffle the playing folder or playlist and the system queue. A nested system playlist in
+    /// the queue is shuffled the next time it starts.
     pub async fn shuffle(&self, shuffle: bool) -> Result<SpotifyPlayerInfo> {
-        if self.active() == ActiveSource::Spotify {
+        self.play_order.set_shuffle(shuffle)?;
+        self.local_player.set_shuffle(shuffle);
+        // Also when Spotify is not playing, so it shuffles the next time it is
+        if self.spotify_commands().is_ok() {
             self.send_spotify(PlayerCommand::Shuffle(shuffle)).await?;
         }
+        self.emit_current_info().await;
         Ok(self.current_info())
     }
 
+    pub async fn repeat(&self, repeat: RepeatMode) -> Result<SpotifyPlayerInfo> {
+        self.play_order.set_repeat(repeat)?;
+        if self.spotify_commands().is_ok() {
+            self.send_spotify(PlayerCommand::Repeat(repeat)).await?;
+        }
+        self.emit_current_info().await;
+        Ok(self.current_info())
+    }
+
+    pub fn volume(&self) -> VolumeLevels {
+        self.volume.levels()
+    }
+
+    pub async fn set_volume(&self, req: VolumeRequest) -> Result<VolumeLevels> {
+        let levels = self.volume.update(req)?;
+        self.emit_current_info().await;
+        Ok(levels)
+    }
+
+    pub fn monitor_channels(&self) -> Vec<MonitorChannel> {
+        self.volume.monitor_channels()
+    }
+
+    pub fn update_monitor_channel(
+        &self,
+        name: &str,
+        req: MonitorChannelRequest,
+    ) -> Result<MonitorChannel> {
+        self.volume.update_monitor_channel(name, req)
+    }
+
+    pub fn solo_monitor_channel(&self, name: Option<&str>) -> Result<Vec<MonitorChannel>> {
+        self.volume.solo_monitor_channel(name)
+    }
+
     fn play_local_ref(
         &self,
         reference: &str,
@@ -474,6 +713,7 @@ impl PlaybackController {
         self.set_active(ActiveSource::Spotify);
         self.send_spotify(PlayerCommand::PlayRef {
             uri: reference.to_string(),
+            // The player itself follows the repeat mode
             repeat: true,
         })
         .await
@@ -509,16 +749,27 @@ impl PlaybackController {
     }
 
     async fn emit_current_info(&self) {
-        let info = self.with_sleep_timer(self.current_info()).await;
+        let info = self.with_controller_state(self.current_info()).await;
         let _ = self.info_sender.send(info);
     }
 
-    async fn with_sleep_timer(&self, mut info: SpotifyPlayerInfo) -> SpotifyPlayerInfo {
+    /// Add the state owned by the controller (sleep timers, play order, volume, monitor alarm) to
+    /// a player's status
+    async fn with_controller_state(&self, mut info: SpotifyPlayerInfo) -> SpotifyPlayerInfo {
         info.sleep_timer = self
             .sleep_timer
             .remaining_time()
             .await
             .map(|remaining| remaining.as_secs() as u32);
+        info.sleep_timer_mode = match self.track_sleep.mode() {
+            Some(mode) => Some(mode),
+            None => info.sleep_timer.map(|_| SleepTimerMode::Time),
+        };
+        info.shuffle = self.play_order.shuffle();
+        info.repeat = self.play_order.repeat();
+        info.volume = Some(self.volume.levels());
+        info.monitor_alarm = *self.monitor_alarm.borrow();
+        info.monitor_disconnected = *self.monitor_disconnected.borrow();
         info
     }
 
@@ -554,6 +805,22 @@ impl PlaybackController {
     }
 
     fn spawn_status_forwarders(&self) {
+        let mut monitor_alarm = self.monitor_alarm.clone();
+        let alarm_controller = self.clone();
+        tokio::spawn(async move {
+            while monitor_alarm.changed().await.is_ok() {
+                alarm_controller.emit_current_info().await;
+            }
+        });
+
+        let mut monitor_disconnected = self.monitor_disconnected.clone();
+        let disconnected_controller = self.clone();
+        tokio::spawn(async move {
+            while monitor_disconnected.changed().await.is_ok() {
+                disconnected_controller.emit_current_info().await;
+            }
+        });
+
         let mut local_status = self.local_player.player_info_channel();
         let local_active = self.active_source.clone();
         let local_sender = self.info_sender.clone();
@@ -565,10 +832,10 @@ impl PlaybackController {
                     ActiveSource::Local | ActiveSource::Youtube
                 ) {
                     let status = local_status.borrow().clone();
-                    let status = local_controller.with_sleep_timer(status).await;
+                    let status = local_controller.with_controller_state(status).await;
                     let _ = local_sender.send(status.clone());
                     if status.status == SpotifyPlayerState::Stopped {
-                        let _ = local_controller.advance_system_queue().await;
+                        let _ = local_controller.advance_system_queue(true).await;
                     }
                 }
             }
@@ -586,42 +853,136 @@ impl PlaybackController {
             while spotify_status.changed().await.is_ok() {
                 if *spotify_active.lock().unwrap() == ActiveSource::Spotify {
                     let status = spotify_status.borrow().clone();
-                    let status = spotify_controller.with_sleep_timer(status).await;
+                    let status = spotify_controller.with_controller_state(status).await;
                     let _ = spotify_sender.send(status.clone());
                     if status.status == SpotifyPlayerState::Stopped {
-                        let _ = spotify_controller.advance_system_queue().await;
+                        let _ = spotify_controller.advance_system_queue(true).await;
                     }
                 }
             }
         });
     }
 
-    async fn advance_system_queue(&self) -> Result<bool> {
-        let should_play = {
+    /// Move on to the next item in the system queue, following the shuffle and repeat mode.
+    /// `item_ended` is set when the current item played to its end, rather than being skipped.
+    async fn advance_system_queue(&self, item_ended: bool) -> Result<bool> {
+        if item_ended && self.system_queue.lock().unwrap().items.is_empty() {
+            self.playback_ended().await;
+            return Ok(false);
+        }
+
+        let repeat = self.play_order.repeat();
+        let step = {
             let mut system_queue = self.system_queue.lock().unwrap();
             if system_queue.items.is_empty() {
-                false
+                return Ok(false);
+            }
+
+            if item_ended && repeat == RepeatMode::One {
+                QueueMutationAction::RestartLeaf
             } else if system_queue.collection_owner_id.is_some()
                 && system_queue.collection_index + 1 < system_queue.collection_items.len()
             {
                 system_queue.collection_index += 1;
-                true
+                QueueMutationAction::RestartLeaf
             } else {
-                system_queue.collection_items.clear();
-                system_queue.collection_index = 0;
-                system_queue.collection_owner_id = None;
-                if system_queue.current_index + 1 < system_queue.items.len() {
-                    system_queue.current_index += 1;
+                let next_index = self.next_queue_index(&system_queue);
+                // Checked first, the sleep timer counts the end of the queue also when it stops
+                let sleep = item_ended && next_index.is_none() && self.track_sleep.queue_ended();
+                match next_index {
+                    _ if sleep => QueueMutationAction::None,
+                    None if repeat == RepeatMode::Off => QueueMutationAction::Stop,
+                    next_index => {
+                        let next_index = match next_index {
+                            Some(next_index) => next_index,
+                            // Play the item that just ended again
+                            None if repeat == RepeatMode::Last => system_queue.current_index,
+                            None => {
+                                system_queue.played.clear();
+                                self.next_queue_index(&system_queue).unwrap_or(0)
+                            }
+                        };
+                        system_queue.collection_items.clear();
+                        system_queue.collection_index = 0;
+                        system_queue.collection_owner_id = None;
+                        system_queue.current_index = next_index;
+                        QueueMutationAction::Restart
+                    }
                 }
-                true
             }
         };
 
-        if should_play {
-            self.play_current_system_item().await?;
+        match step {
+            // The sleep timer elapsed or the queue played to its end, the player already stopped
+            QueueMutationAction::None | QueueMutationAction::Stop if item_ended => {
+                self.playback_ended().await;
+                Ok(false)
+            }
+            step => {
+                self.apply_queue_mutation_action(step).await?;
+                Ok(true)
+            }
         }
+    }
 
-        Ok(should_play)
+    /// Playback stopped on its own, a track sleep timer that did not elapse must not pause
+    /// whatever plays next
+    async fn playback_ended(&self) {
+        self.track_sleep.set(None);
+        self.emit_current_info().await;
+    }
+
+    /// The item after the current one, or a random one that did not play yet when shuffling.
+    /// `None` at the end of the queue.
+    fn next_queue_index(&self, system_queue: &SystemQueue) -> Option<usize> {
+        if !self.play_order.shuffle() {
+            return Some(system_queue.current_index + 1)
+                .filter(|index| *index < system_queue.items.len());
+        }
+
+        let current_id = system_queue
+            .items
+            .get(system_queue.current_index)
+            .map(|item| &item.id);
+        let unplayed = system_queue
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| {
+                Some(&item.id) != current_id && !system_queue.played.contains(&item.id)
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        unplayed.choose(&mut rand::thread_rng()).copied()
+    }
+
+    /// Step back one item in the system queue, restarting the first item when there is no
+    /// previous one. Returns `false` when no system queue is active.
+    async fn retreat_system_queue(&self) -> Result<bool> {
+        let action = {
+            let mut system_queue = self.system_queue.lock().unwrap();
+            if system_queue.items.is_empty() {
+                return Ok(false);
+            }
+
+            if system_queue.collection_owner_id.is_some() && system_queue.collection_index > 0 {
+                system_queue.collection_index -= 1;
+                QueueMutationAction::RestartLeaf
+            } else {
+                system_queue.collection_items.clear();
+                system_queue.collection_index = 0;
+                system_queue.collection_owner_id = None;
+                system_queue.current_index = if self.play_order.shuffle() {
+                    previously_played_index(&mut system_queue)
+                } else {
+                    system_queue.current_index.saturating_sub(1)
+                };
+                QueueMutationAction::Restart
+            }
+        };
+
+        self.apply_queue_mutation_action(action).await?;
+        Ok(true)
     }
 
     async fn play_current_system_item(&self) -> Result<()> {
@@ -634,16 +995,23 @@ impl PlaybackController {
                 .ok_or_else(|| anyhow!("System playlist queue is empty"))?
         };
 
-        if let Some(id) = item.reference.strip_prefix("system:playlist:") {
-            let playlist = self
-                .playlists
-                .get(id)
-                .ok_or_else(|| anyhow!("Unknown system playlist: {id}"))?;
-            if playlist.items.is_empty() {
+        {
+            let mut system_queue = self.system_queue.lock().unwrap();
+            if !system_queue.played.contains(&item.id) {
+                system_queue.played.push(item.id.clone());
+            }
+        }
+
+        if item.reference.starts_with("system:playlist:") {
+            let mut tracks = self.expand_queue_item(&item)?;
+            if tracks.is_empty() {
                 anyhow::bail!("System playlist is empty");
             }
+            if self.play_order.shuffle() {
+                tracks.shuffle(&mut rand::thread_rng());
+            }
             let mut system_queue = self.system_queue.lock().unwrap();
-            system_queue.collection_items = playlist.items;
+            system_queue.collection_items = tracks;
             system_queue.collection_index = 0;
             system_queue.collection_owner_id = Some(item.id);
         }
@@ -654,11 +1022,11 @@ impl PlaybackController {
     async fn play_current_system_leaf(&self) -> Result<()> {
         let item = {
             let system_queue = self.system_queue.lock().unwrap();
-            if let Some(item) = system_queue
+            if let Some(nested) = system_queue
                 .collection_items
                 .get(system_queue.collection_index)
             {
-                item.clone()
+                nested.item.clone()
             } else {
                 system_queue
                     .items
@@ -670,9 +1038,20 @@ impl PlaybackController {
         self.play_queue_leaf(&item).await
     }
 
+    /// The tracks of a queue item, nested system playlists are expanded recursively
+    fn expand_queue_item(&self, item: &SystemPlaylistItem) -> Result<Vec<NestedPlaylistItem>> {
+        match item.reference.strip_prefix("system:playlist:") {
+            Some(id) => self.playlists.flatten(id),
+            None => Ok(vec![NestedPlaylistItem {
+                item: item.clone(),
+                playlists: Vec::new(),
+            }]),
+        }
+    }
+
     async fn play_queue_leaf(&self, item: &SystemPlaylistItem) -> Result<()> {
         if item.reference.starts_with("system:playlist:") {
-            anyhow::bail!("Nested system playlists are not supported in the queue");
+            anyhow::bail!("System playlists are expanded before they are played");
         }
 
         if item.reference.starts_with("local:file:")
@@ -745,7 +1124,10 @@ impl PlaybackController {
             ActiveSource::None => {}
         }
         self.set_active(ActiveSource::None);
-        let _ = self.info_sender.send(SpotifyPlayerInfo::stopped());
+        let info = self
+            .with_controller_state(SpotifyPlayerInfo::stopped())
+            .await;
+        let _ = self.info_sender.send(info);
         Ok(())
     }
 
@@ -753,6 +1135,7 @@ impl PlaybackController {
         match action {
             QueueMutationAction::None => Ok(()),
             QueueMutationAction::Restart => self.play_current_system_item().await,
+            QueueMutationAction::RestartLeaf => self.play_current_system_leaf().await,
             QueueMutationAction::Stop => self.stop_active().await,
         }
     }
@@ -761,9 +1144,34 @@ impl PlaybackController {
 enum QueueMutationAction {
     None,
     Restart,
+    RestartLeaf,
     Stop,
 }
 
+/// The index of the item played before the current one when shuffling, which makes the current
+/// one unplayed again. Stays on the current item when there is none.
+fn previously_played_index(system_queue: &mut SystemQueue) -> usize {
+    let current = system_queue.current_index;
+    let Some(current_id) = system_queue.items.get(current).map(|item| item.id.clone()) else {
+        return current;
+    };
+    let Some(position) = system_queue.played.iter().position(|id| *id == current_id) else {
+        return current;
+    };
+    let previous = system_queue.played[..position]
+        .iter()
+        .rev()
+        .find_map(|id| system_queue.items.iter().position(|item| item.id == *id));
+
+    match previous {
+        Some(previous) => {
+            system_queue.played.remove(position);
+            previous
+        }
+        None => current,
+    }
+}
+
 fn moved_current_index(current: usize, from: usize, to: usize) -> usize {
     if current == from {
         to
@@ -0,0 +1,577 @@
+use crate::local_audio::LocalAudioLibrary;
+use crate::system_playlists::{SystemPlaylist, SystemPlaylistItem, SystemPlaylistStore};
+use anyhow::{Result, anyhow};
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Characters escaped in the path of a `file://` URI
+const FILE_URI_PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u,
+    Xspf,
+    Json,
+}
+
+/// The libraries that `local:`, `youtube:` and `system:playlist:` references point into
+pub struct PlaylistLibraries {
+    pub local: LocalAudioLibrary,
+    pub youtube: LocalAudioLibrary,
+    pub system: SystemPlaylistStore,
+}
+
+/// A track read from a playlist file, before it is matched to a library
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    /// The line in an M3U file, or the number of the track in XSPF and JSON
+    pub line: usize,
+    pub location: String,
+    pub title: Option<String>,
+    /// Only set by JSON playlists, which hold the original item
+    pub item: Option<JsonPlaylistItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonPlaylistItem {
+    pub source: String,
+    pub kind: String,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonPlaylist {
+    #[serde(default)]
+    name: Option<String>,
+    items: Vec<JsonPlaylistItem>,
+}
+
+pub struct ParsedPlaylist {
+    pub name: Option<String>,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnresolvedEntry {
+    pub line: usize,
+    pub location: String,
+    pub reason: String,
+}
+
+/// A playlist item without an id, ready to be added to a system playlist
+#[derive(Debug, Clone)]
+pub struct ImportedItem {
+    pub source: String,
+    pub kind: String,
+    pub reference: String,
+    pub title: String,
+}
+
+impl PlaylistFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "m3u" | "m3u8" => Ok(PlaylistFormat::M3u),
+            "xspf" => Ok(PlaylistFormat::Xspf),
+            "json" => Ok(PlaylistFormat::Json),
+            _ => anyhow::bail!("Unsupported playlist format: {name}, expected m3u8, xspf or json"),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "audio/x-mpegurl; charset=utf-8",
+            PlaylistFormat::Xspf => "application/xspf+xml; charset=utf-8",
+            PlaylistFormat::Json => "application/json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "m3u8",
+            PlaylistFormat::Xspf => "xspf",
+            PlaylistFormat::Json => "json",
+        }
+    }
+}
+
+/// Write a system playlist in the given format. Local files are written as paths and Spotify
+/// items as URIs, items that have neither (e.g. nested system playlists) are left out.
+pub fn export(
+    playlist: &SystemPlaylist,
+    format: PlaylistFormat,
+    libraries: &PlaylistLibraries,
+) -> Result<String> {
+    if format == PlaylistFormat::Json {
+        return Ok(serde_json::to_string_pretty(playlist)?);
+    }
+
+    let mut tracks = Vec::new();
+    for item in &playlist.items {
+        match item_location(item, libraries) {
+            Some(location) => tracks.push((location, item.title.as_str())),
+            None => log::debug!(
+                "Leaving {} out of the export of {}",
+                item.reference,
+                playlist.name
+            ),
+        }
+    }
+
+    Ok(match format {
+        PlaylistFormat::M3u => write_m3u(&playlist.name, &tracks),
+        _ => write_xspf(&playlist.name, &tracks),
+    })
+}
+
+pub fn parse(format: PlaylistFormat, content: &str) -> Result<ParsedPlaylist> {
+    match format {
+        PlaylistFormat::M3u => Ok(parse_m3u(content)),
+        PlaylistFormat::Xspf => parse_xspf(content),
+        PlaylistFormat::Json => parse_json(content),
+    }
+}
+
+/// Match the entries of a playlist file to library files and Spotify URIs
+pub fn resolve(
+    entries: Vec<PlaylistEntry>,
+    libraries: &PlaylistLibraries,
+) -> (Vec<ImportedItem>, Vec<UnresolvedEntry>) {
+    let mut items = Vec::new();
+    let mut unresolved = Vec::new();
+
+    for entry in entries {
+        match resolve_entry(&entry, libraries) {
+            Ok(item) => items.push(item),
+            Err(e) => unresolved.push(UnresolvedEntry {
+                line: entry.line,
+                location: entry.location,
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    (items, unresolved)
+}
+
+fn resolve_entry(entry: &PlaylistEntry, libraries: &PlaylistLibraries) -> Result<ImportedItem> {
+    if let Some(item) = &entry.item {
+        let title = Some(item.title.clone()).filter(|title| !title.is_empty());
+        let mut imported = resolve_location(&item.reference, title, libraries)?;
+        imported.kind = item.kind.clone();
+        return Ok(imported);
+    }
+
+    resolve_location(&entry.location, entry.title.clone(), libraries)
+}
+
+fn resolve_location(
+    location: &str,
+    title: Option<String>,
+    libraries: &PlaylistLibraries,
+) -> Result<ImportedItem> {
+    if let Some(uri) = spotify_uri(location) {
+        let kind = if uri.starts_with("spotify:track:") || uri.starts_with("spotify:episode:") {
+            "file"
+        } else {
+            "playlist"
+        };
+        return Ok(ImportedItem {
+            source: "spotify".to_string(),
+            kind: kind.to_string(),
+            title: title.unwrap_or_else(|| uri.clone()),
+            reference: uri,
+        });
+    }
+
+    // Only in JSON exports, the other formats leave nested system playlists out
+    if let Some(id) = location.strip_prefix("system:playlist:") {
+        let playlist = libraries
+            .system
+            .get(id)
+            .ok_or_else(|| anyhow!("Unknown system playlist: {id}"))?;
+        return Ok(ImportedItem {
+            source: "system".to_string(),
+            kind: "playlist".to_string(),
+            reference: location.to_string(),
+            title: title.unwrap_or(playlist.name),
+        });
+    }
+
+    if location.starts_with("local:") || location.starts_with("root:") {
+        return library_item("local", location.to_string(), title, &libraries.local);
+    }
+    if location.starts_with("youtube:") {
+        return library_item("youtube", location.to_string(), title, &libraries.youtube);
+    }
+
+    let path = file_path(location)?;
+    if let Ok(reference) = libraries.local.find_file_ref(&path) {
+        return library_item(
+            "local",
+            format!("local:file:{reference}"),
+            title,
+            &libraries.local,
+        );
+    }
+    if let Ok(reference) = libraries.youtube.find_file_ref(&path) {
+        return library_item(
+            "youtube",
+            format!("youtube:file:{reference}"),
+            title,
+            &libraries.youtube,
+        );
+    }
+
+    anyhow::bail!("Not found in the local or YouTube library")
+}
+
+fn library_item(
+    source: &str,
+    reference: String,
+    title: Option<String>,
+    library: &LocalAudioLibrary,
+) -> Result<ImportedItem> {
+    let path = library.resolve_ref(&reference)?;
+    let kind = if path.is_dir() { "folder" } else { "file" };
+    // Fall back to the title in the file's tags, like the library listing does
+    let title = match title {
+        Some(title) => title,
+        None if path.is_file() => library
+            .resolve_to_files(&reference)?
+            .into_iter()
+            .next()
+            .map(|entry| entry.name)
+            .unwrap_or_else(|| reference.clone()),
+        None => path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| reference.clone()),
+    };
+
+    Ok(ImportedItem {
+        source: source.to_string(),
+        kind: kind.to_string(),
+        reference,
+        title,
+    })
+}
+
+fn item_location(item: &SystemPlaylistItem, libraries: &PlaylistLibraries) -> Option<String> {
+    if item.reference.starts_with("spotify:") {
+        return Some(item.reference.clone());
+    }
+
+    let library = if item.reference.starts_with("local:") || item.reference.starts_with("root:") {
+        &libraries.local
+    } else if item.reference.starts_with("youtube:") {
+        &libraries.youtube
+    } else {
+        return None;
+    };
+
+    match library.resolve_ref(&item.reference) {
+        Ok(path) => Some(path.display().to_string()),
+        Err(e) => {
+            log::warn!("Failed to export {}: {e}", item.reference);
+            None
+        }
+    }
+}
+
+/// A Spotify URI, also for `open.spotify.com` links
+fn spotify_uri(location: &str) -> Option<String> {
+    if location.starts_with("spotify:") {
+        return Some(location.to_string());
+    }
+
+    let path = location
+        .strip_prefix("https://open.spotify.com/")
+        .or_else(|| location.strip_prefix("http://open.spotify.com/"))?;
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut parts = path.split('/').filter(|part| !part.is_empty());
+    let mut kind = parts.next()?;
+    // Localized links look like /intl-nl/track/<id>
+    if kind.starts_with("intl-") {
+        kind = parts.next()?;
+    }
+    let id = parts.next()?;
+    Some(format!("spotify:{kind}:{id}"))
+}
+
+fn file_path(location: &str) -> Result<std::path::PathBuf> {
+    let path = match location.strip_prefix("file://") {
+        Some(path) => percent_decode_str(path)
+            .decode_utf8()
+            .map_err(|_| anyhow!("Invalid file URI"))?
+            .to_string(),
+        None if location.contains("://") => anyhow::bail!("Unsupported location"),
+        None => location.to_string(),
+    };
+    // Playlists written on Windows
+    let path = path.replace('\\', "/");
+    Ok(Path::new(&path).to_path_buf())
+}
+
+fn write_m3u(name: &str, tracks: &[(String, &str)]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    out.push_str(&format!("#PLAYLIST:{}\n", single_line(name)));
+    for (location, title) in tracks {
+        out.push_str(&format!("#EXTINF:-1,{}\n{location}\n", single_line(title)));
+    }
+    out
+}
+
+fn write_xspf(name: &str, tracks: &[(String, &str)]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    out.push_str(&format!(
+        "  <title>{}</title>\n  <trackList>\n",
+        escape_xml(name)
+    ));
+    for (location, title) in tracks {
+        let location = if location.starts_with('/') {
+            format!("file://{}", utf8_percent_encode(location, FILE_URI_PATH))
+        } else {
+            location.clone()
+        };
+        out.push_str(&format!(
+            "    <track>\n      <location>{}</location>\n      <title>{}</title>\n    </track>\n",
+            escape_xml(&location),
+            escape_xml(title)
+        ));
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+fn parse_m3u(content: &str) -> ParsedPlaylist {
+    let mut name = None;
+    let mut title = None;
+    let mut entries = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(playlist) = line.strip_prefix("#PLAYLIST:") {
+            name = Some(playlist.trim().to_string());
+        } else if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<duration> [attributes],<title>
+            title = info
+                .split_once(',')
+                .map(|(_, title)| title.trim().to_string())
+                .filter(|title| !title.is_empty());
+        } else if !line.starts_with('#') {
+            entries.push(PlaylistEntry {
+                line: index + 1,
+                location: line.to_string(),
+                title: title.take(),
+                item: None,
+            });
+        }
+    }
+
+    ParsedPlaylist { name, entries }
+}
+
+/// A minimal XSPF reader, it only looks at the title of the playlist and the location and title
+/// of its tracks
+fn parse_xspf(content: &str) -> Result<ParsedPlaylist> {
+    let track_list = element_text(content, "trackList")
+        .ok_or_else(|| anyhow!("Invalid XSPF playlist: missing trackList"))?;
+    let name = content
+        .split("<trackList")
+        .next()
+        .and_then(|header| element_text(header, "title"))
+        .map(unescape_xml);
+
+    let mut entries = Vec::new();
+    let mut rest = track_list;
+    while let Some(track) = element_text(rest, "track") {
+        let end = rest.find("</track>").map(|end| end + "</track>".len());
+        entries.push(PlaylistEntry {
+            line: entries.len() + 1,
+            location: element_text(track, "location")
+                .map(unescape_xml)
+                .unwrap_or_default(),
+            title: element_text(track, "title")
+                .map(unescape_xml)
+                .filter(|title| !title.is_empty()),
+            item: None,
+        });
+        match end {
+            Some(end) => rest = &rest[end..],
+            None => break,
+        }
+    }
+
+    Ok(ParsedPlaylist { name, entries })
+}
+
+fn parse_json(content: &str) -> Result<ParsedPlaylist> {
+    let playlist: JsonPlaylist =
+        serde_json::from_str(content).map_err(|e| anyhow!("Invalid JSON playlist: {e}"))?;
+
+    Ok(ParsedPlaylist {
+        name: playlist.name,
+        entries: playlist
+            .items
+            .into_iter()
+            .enumerate()
+            .map(|(index, item)| PlaylistEntry {
+                line: index + 1,
+                location: item.reference.clone(),
+                title: Some(item.title.clone()),
+                item: Some(item),
+            })
+            .collect(),
+    })
+}
+
+/// The content of the first `<tag>` element, which may have attributes
+fn element_text<'a>(content: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{tag}");
+    let mut search = content;
+    loop {
+        let start = search.find(&open)?;
+        let after = &search[start + open.len()..];
+        // Skip elements that merely start with the same name, e.g. <trackNum> for <track>
+        if after.starts_with('>') || after.starts_with(char::is_whitespace) {
+            let body = &after[after.find('>')? + 1..];
+            let end = body.find(&format!("</{tag}>"))?;
+            return Some(body[..end].trim());
+        }
+        if after.starts_with("/>") {
+            return Some("");
+        }
+        search = after;
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest
+            .find(';')
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+        match entity {
+            Some((decoded, end)) => {
+                out.push(decoded);
+                rest = &rest[end + 1..];
+            }
+            // Not an entity we know, keep it as it is
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The character of an entity between its `&` and `;`, e.g. `amp`, `#38` or `#x26`
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "amp" => Some('&'),
+        _ => {
+            let number = entity.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn m3u_round_trips_titles_and_locations() {
+        let tracks = vec![
+            ("/music/Lullabies/01 Brahms.ogg".to_string(), "Brahms"),
+            (
+                "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_string(),
+                "Gymnopédie",
+            ),
+        ];
+        let parsed = parse_m3u(&write_m3u("Bedtime", &tracks));
+
+        assert_eq!(parsed.name.as_deref(), Some("Bedtime"));
+        assert_eq!(parsed.entries.len(), 2);
+        assert_eq!(parsed.entries[0].location, "/music/Lullabies/01 Brahms.ogg");
+        assert_eq!(parsed.entries[0].title.as_deref(), Some("Brahms"));
+        assert_eq!(parsed.entries[1].line, 6);
+        assert_eq!(parsed.entries[1].title.as_deref(), Some("Gymnopédie"));
+    }
+
+    #[test]
+    fn xspf_round_trips_escaped_titles_and_file_uris() {
+        let tracks = vec![("/music/Rock & Roll/Lullaby #1.ogg".to_string(), "<Lullaby>")];
+        let xspf = write_xspf("Bedtime & naps", &tracks);
+        assert!(xspf.contains("file:///music/Rock%20&amp;%20Roll/Lullaby%20%231.ogg"));
+
+        let parsed = parse_xspf(&xspf).unwrap();
+        assert_eq!(parsed.name.as_deref(), Some("Bedtime & naps"));
+        assert_eq!(parsed.entries.len(), 1);
+        assert_eq!(parsed.entries[0].title.as_deref(), Some("<Lullaby>"));
+        assert_eq!(
+            file_path(&parsed.entries[0].location).unwrap(),
+            Path::new("/music/Rock & Roll/Lullaby #1.ogg")
+        );
+    }
+
+    #[test]
+    fn numeric_character_references_are_unescaped() {
+        assert_eq!(
+            unescape_xml("Rock &#38; Roll &#x26; Blues &amp;#38; Caf&#xE9; &nbsp; AT&T"),
+            "Rock & Roll & Blues &#38; Café &nbsp; AT&T"
+        );
+    }
+
+    #[test]
+    fn spotify_links_are_converted_to_uris() {
+        assert_eq!(
+            spotify_uri("https://open.spotify.com/intl-nl/track/4uLU6hMCjMI75M1A2tKUQC?si=abc"),
+            Some("spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_string())
+        );
+        assert_eq!(spotify_uri("/music/track.ogg"), None);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::atomic_file;
+use crate::playback_controller::PlaybackController;
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Datelike, Days, Local, NaiveTime, TimeZone, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{MissedTickBehavior, interval};
+
+const SCHEDULER_TICK: Duration = Duration::from_secs(10);
+/// Earlier versions of the routines file that are kept next to it
+const BACKUPS: usize = 3;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RoutineDay {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+/// Something to play at a fixed time of day, e.g. a bedtime playlist with a sleep timer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Routine {
+    pub id: String,
+    pub name: String,
+    pub enabled: bool,
+    /// Local time of day at which the routine runs (`HH:MM`)
+    pub time: String,
+    /// The days on which the routine runs, every day when empty
+    pub days: Vec<RoutineDay>,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    /// Music volume (0.0 - 1.0) to set before playing
+    pub volume: Option<f64>,
+    pub sleep_timer_min: Option<u32>,
+    /// Fade the music in from silence over this many seconds
+    pub fade_in_s: Option<u32>,
+    /// Skip the next run only, cleared once it has been skipped
+    pub skip_next: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoutineStatus {
+    #[serde(flatten)]
+    pub routine: Routine,
+    /// Unix epoch (seconds) of the next time the routine will play
+    pub next_run_at: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoutineRequest {
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub time: String,
+    #[serde(default)]
+    pub days: Vec<RoutineDay>,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub volume: Option<f64>,
+    pub sleep_timer_min: Option<u32>,
+    pub fade_in_s: Option<u32>,
+}
+
+#[derive(Clone)]
+pub struct RoutineStore {
+    path: PathBuf,
+    routines: Arc<Mutex<Vec<Routine>>>,
+    /// Why the file could not be read, it is left alone until it is fixed by hand
+    load_error: Option<String>,
+}
+
+impl RoutineStore {
+    pub fn new(path: PathBuf) -> Self {
+        let (routines, load_error) = match read_routines(&path) {
+            Ok(routines) => (routines, None),
+            Err(e) => {
+                let backups = atomic_file::backups(&path, BACKUPS);
+                log::error!(
+                    "Failed to load routines, changes will not be saved until {} is fixed or restored from a backup ({}): {e:#}",
+                    path.display(),
+                    backups
+                        .iter()
+                        .map(|backup| backup.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                (Vec::new(), Some(format!("{e:#}")))
+            }
+        };
+
+        Self {
+            path,
+            routines: Arc::new(Mutex::new(routines)),
+            load_error,
+        }
+    }
+
+    pub fn list(&self) -> Vec<RoutineStatus> {
+        let now = Local::now();
+        self.routines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|routine| status(routine, &now))
+            .collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<RoutineStatus> {
+        let now = Local::now();
+        self.routines
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|routine| routine.id == id)
+            .map(|routine| status(routine, &now))
+    }
+
+    pub fn create(&self, req: RoutineRequest) -> Result<RoutineStatus> {
+        validate(&req)?;
+        self.ensure_writable()?;
+        let now = now_epoch_secs();
+        let routine = Routine {
+            id: format!("routine-{now}-{}", rand::random::<u32>()),
+            name: req.name,
+            enabled: req.enabled,
+            time: req.time,
+            days: req.days,
+            reference: req.reference,
+            volume: req.volume,
+            sleep_timer_min: req.sleep_timer_min,
+            fade_in_s: req.fade_in_s,
+            skip_next: false,
+            created_at: now,
+            updated_at: now,
+        };
+
+        let mut routines = self.routines.lock().unwrap();
+        routines.push(routine.clone());
+        self.persist_locked(&routines)?;
+        Ok(status(&routine, &Local::now()))
+    }
+
+    pub fn update(&self, id: &str, req: RoutineRequest) -> Result<RoutineStatus> {
+        validate(&req)?;
+        self.modify(id, |routine| {
+            routine.name = req.name;
+            routine.enabled = req.enabled;
+            routine.time = req.time;
+            routine.days = req.days;
+            routine.reference = req.reference;
+            routine.volume = req.volume;
+            routine.sleep_timer_min = req.sleep_timer_min;
+            routine.fade_in_s = req.fade_in_s;
+        })
+    }
+
+    pub fn delete(&self, id: &str) -> Result<()> {
+        self.ensure_writable()?;
+        let mut routines = self.routines.lock().unwrap();
+        let index = routines
+            .iter()
+            .position(|routine| routine.id == id)
+            .ok_or_else(|| anyhow!("Unknown routine: {id}"))?;
+        routines.remove(index);
+        self.persist_locked(&routines)
+    }
+
+    pub fn set_skip_next(&self, id: &str, skip_next: bool) -> Result<RoutineStatus> {
+        self.modify(id, |routine| routine.skip_next = skip_next)
+    }
+
+    /// Start running the routines when they are due
+    pub fn start_scheduler(&self, playback: Arc<PlaybackController>) {
+        let store = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = interval(SCHEDULER_TICK);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            // Routines that were due while the service was down are not caught up on
+            let mut last_check = Local::now();
+
+            loop {
+                ticker.tick().await;
+                let now = Local::now();
+                for routine in store.due(&last_check, &now) {
+                    store.run(&routine, &playback).await;
+                }
+                last_check = now;
+            }
+        });
+    }
+
+    fn due(&self, after: &DateTime<Local>, until: &DateTime<Local>) -> Vec<Routine> {
+        self.routines
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|routine| routine.enabled && is_due(routine, after, until))
+            .cloned()
+            .collect()
+    }
+
+    async fn run(&self, routine: &Routine, playback: &PlaybackController) {
+        if routine.skip_next {
+            log::info!("Skipping routine {}", routine.name);
+            if let Err(e) = self.set_skip_next(&routine.id, false) {
+                log::warn!("Failed to clear skip of routine {}: {e}", routine.name);
+            }
+            return;
+        }
+
+        log::info!("Running routine {}", routine.name);
+        if let Err(e) = playback.run_routine(routine).await {
+            log::error!("Failed to run routine {}: {e}", routine.name);
+        }
+    }
+
+    fn modify(&self, id: &str, change: impl FnOnce(&mut Routine)) -> Result<RoutineStatus> {
+        self.ensure_writable()?;
+        let mut routines = self.routines.lock().unwrap();
+        let routine = routines
+            .iter_mut()
+            .find(|routine| routine.id == id)
+            .ok_or_else(|| anyhow!("Unknown routine: {id}"))?;
+        change(routine);
+        routine.updated_at = now_epoch_secs();
+        let updated = status(routine, &Local::now());
+        self.persist_locked(&routines)?;
+        Ok(updated)
+    }
+
+    /// Never overwrite a file that could not be read, the routines in it would be lost
+    fn ensure_writable(&self) -> Result<()> {
+        match &self.load_error {
+            Some(e) => Err(anyhow!(
+                "Routines are read-only because {} could not be loaded: {e}",
+                self.path.display()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn persist_locked(&self, routines: &[Routine]) -> Result<()> {
+        self.ensure_writable()?;
+        let json = serde_json::to_vec_pretty(routines)?;
+        atomic_file::write_with_backups(&self.path, &json, BACKUPS)
+    }
+}
+
+impl RoutineDay {
+    fn weekday(self) -> Weekday {
+        match self {
+            RoutineDay::Monday => Weekday::Mon,
+            RoutineDay::Tuesday => Weekday::Tue,
+            RoutineDay::Wednesday => Weekday::Wed,
+            RoutineDay::Thursday => Weekday::Thu,
+            RoutineDay::Friday => Weekday::Fri,
+            RoutineDay::Saturday => Weekday::Sat,
+            RoutineDay::Sunday => Weekday::Sun,
+        }
+    }
+}
+
+fn validate(req: &RoutineRequest) -> Result<()> {
+    if req.name.trim().is_empty() {
+        anyhow::bail!("Routine name must not be empty");
+    }
+    parse_time(&req.time)?;
+    if req.reference.trim().is_empty() {
+        anyhow::bail!("Routine ref must not be empty");
+    }
+    if let Some(volume) = req.volume
+        && !(0.0..=1.0).contains(&volume)
+    {
+        anyhow::bail!("Routine volume must be between 0.0 and 1.0");
+    }
+    Ok(())
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| anyhow!("Invalid routine time, expected HH:MM: {time}"))
+}
+
+fn status(routine: &Routine, now: &DateTime<Local>) -> RoutineStatus {
+    let next_run_at = if routine.enabled {
+        // A skipped run does not count as the next one
+        occurrences(routine, now)
+            .nth(usize::from(routine.skip_next))
+            .map(|run| run.timestamp())
+    } else {
+        None
+    };
+
+    RoutineStatus {
+        routine: routine.clone(),
+        next_run_at,
+    }
+}
+
+/// Whether the routine has a run in the period (after, until]
+fn is_due<Tz: TimeZone>(routine: &Routine, after: &DateTime<Tz>, until: &DateTime<Tz>) -> bool {
+    occurrences(routine, after)
+        .next()
+        .is_some_and(|run| run <= *until)
+}
+
+/// The runs of a routine after the given moment, for the coming two weeks
+fn occurrences<Tz: TimeZone>(
+    routine: &Routine,
+    after: &DateTime<Tz>,
+) -> impl Iterator<Item = DateTime<Tz>> {
+    let time = parse_time(&routine.time).ok();
+    let days = routine.days.clone();
+    let start = after.date_naive();
+    let timezone = after.timezone();
+    let after = after.clone();
+
+    (0..14)
+        .filter_map(move |offset| start.checked_add_days(Days::new(offset)))
+        .filter(move |date| {
+            days.is_empty() || days.iter().any(|day| day.weekday() == date.weekday())
+        })
+        .filter_map(move |date| {
+            timezone
+                .from_local_datetime(&date.and_time(time?))
+                .earliest()
+        })
+        .filter(move |run| *run > after)
+}
+
+fn read_routines(path: &PathBuf) -> Result<Vec<Routine>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let routines = serde_json::from_slice(&bytes)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(routines)
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn now_epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn routine(time: &str, days: Vec<RoutineDay>) -> Routine {
+        Routine {
+            id: "routine".to_string(),
+            name: "Bedtime".to_string(),
+            enabled: true,
+            time: time.to_string(),
+            days,
+            reference: "system:playlist:bedtime".to_string(),
+            volume: None,
+            sleep_timer_min: None,
+            fade_in_s: None,
+            skip_next: false,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        // 2025-01-06 is a monday
+        DateTime::parse_from_rfc3339(&format!("2025-01-06T{time}Z"))
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn routines_are_due_once_their_time_has_passed() {
+        let routine = routine("19:30", Vec::new());
+        assert!(is_due(&routine, &at("19:29:55"), &at("19:30:05")));
+        assert!(is_due(&routine, &at("19:29:55"), &at("19:30:00")));
+        assert!(!is_due(&routine, &at("19:30:00"), &at("19:30:10")));
+        assert!(!is_due(&routine, &at("19:29:40"), &at("19:29:50")));
+    }
+
+    #[test]
+    fn routines_only_run_on_their_days() {
+        let weekend = routine("06:30", vec![RoutineDay::Saturday, RoutineDay::Sunday]);
+        assert!(!is_due(&weekend, &at("06:29:55"), &at("06:30:05")));
+
+        let next = occurrences(&weekend, &at("06:29:55")).next().unwrap();
+        assert_eq!(next.weekday(), Weekday::Sat);
+        assert_eq!(next.time(), NaiveTime::from_hms_opt(6, 30, 0).unwrap());
+    }
+}
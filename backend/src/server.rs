@@ -1,20 +1,34 @@
+use crate::ducking::DuckingController;
 use crate::local_audio::{LocalAudioLibrary, LocalAudioPlayer};
-use crate::music_timer::MusicVolume;
+use crate::monitor_watchdog::MonitorWatchdog;
+use crate::music_timer::{MusicFader, MusicVolume, TrackSleepTimer};
 use crate::pipeline::AudioPipeline;
 use crate::pipeline::audio_bridge::AudioBridge;
+use crate::pipeline::audio_pipeline::{PipelineHandle, REPLACE_PIPELINE_MESSAGE};
+use crate::pipeline::monitor_recovery::MonitorRecovery;
+use crate::pipeline::pipeline_health::PipelineHealth;
+use crate::play_order::PlayOrder;
 use crate::playback_controller::PlaybackController;
+use crate::routines::RoutineStore;
+use crate::settings_reload::SettingsReloader;
+use crate::sound_detector::SoundDetector;
 use crate::spotify_client::{SpotifyClient, UnauthenticatedSpotifyClient};
 use crate::spotify_player::SpotifyPlayerInfo;
 use crate::spotify_sink::SinkEvent;
 use crate::system_playlists::SystemPlaylistStore;
-use crate::webserver::start_http_server;
+use crate::volume_control::{MixerPads, VolumeControl};
+use crate::webserver::{HttpServices, start_http_server};
 
+use crate::api_auth::ApiAuth;
 use crate::app_settings::ApplicationSettings;
 use crate::data_paths;
+use crate::metrics;
 use gstreamer as gst;
 use gstreamer::prelude::{Cast, ElementExt, GstObjectExt};
 use gstreamer::{ClockTime, Element};
 use gstreamer_app::AppSrc;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::mpsc::sync_channel;
 use tokio;
@@ -24,48 +38,143 @@ use tokio::time::Duration;
 
 pub struct CareChordsServer {
     spotify: Arc<UnauthenticatedSpotifyClient>,
-    pipeline: Arc<AudioPipeline>,
-    monitor_url: String,
+    pipeline: Arc<PipelineHandle>,
+    reloader: Arc<SettingsReloader>,
+    health: Arc<PipelineHealth>,
+    recovery: Arc<MonitorRecovery>,
+    http_address: SocketAddr,
+    http_tls: Option<(PathBuf, PathBuf)>,
+    auth: Arc<ApiAuth>,
     local_library: LocalAudioLibrary,
     youtube_library: LocalAudioLibrary,
     local_player: Arc<LocalAudioPlayer>,
     system_playlists: SystemPlaylistStore,
+    routines: RoutineStore,
     audio_bridge: Arc<AudioBridge>,
+    fader: Arc<MusicFader>,
     music_volume: Arc<MusicVolume>,
+    track_sleep: Arc<TrackSleepTimer>,
+    play_order: Arc<PlayOrder>,
+    sound_detector: Arc<SoundDetector>,
+    ducking: Arc<DuckingController>,
+    watchdog: Arc<MonitorWatchdog>,
+    volume: Arc<VolumeControl>,
 }
 
 impl CareChordsServer {
     pub fn new(settings: &ApplicationSettings) -> Self {
         let (sender, receiver) = sync_channel::<SinkEvent>(10);
         let music_volume = Arc::new(MusicVolume::new(1.0));
-        let audio_bridge = Arc::new(AudioBridge::new(receiver, music_volume.clone()));
+        let fade_gain = Arc::new(MusicVolume::new(1.0));
+        let track_sleep = Arc::new(TrackSleepTimer::default());
+        let play_order = Arc::new(PlayOrder::new(data_paths::play_order_file()));
+        let ducking_gain = Arc::new(MusicVolume::new(1.0));
+        let volume = Arc::new(VolumeControl::new(
+            data_paths::volume_file(),
+            &settings.monitors,
+        ));
+        let audio_bridge = Arc::new(AudioBridge::new(
+            receiver,
+            vec![
+                volume.music_gain(),
+                music_volume.clone(),
+                fade_gain.clone(),
+                ducking_gain.clone(),
+            ],
+        ));
+
+        let pipeline = Arc::new(PipelineHandle::new(AudioPipeline::new(settings).unwrap()));
+        let local_library = LocalAudioLibrary::new(&settings.local_audio);
+        let youtube_library = LocalAudioLibrary::new_youtube(&settings.youtube_audio);
+        let sound_detector = Arc::new(SoundDetector::new(&settings.sound_detection));
+        let ducking = Arc::new(DuckingController::new(&settings.ducking, ducking_gain));
+        let reloader = Arc::new(SettingsReloader::new(
+            settings.clone(),
+            pipeline.clone(),
+            local_library.clone(),
+            youtube_library.clone(),
+            volume.clone(),
+            sound_detector.clone(),
+            ducking.clone(),
+        ));
 
         Self {
-            spotify: Arc::new(SpotifyClient::new_with_sender(sender.clone())),
-            pipeline: Arc::new(AudioPipeline::new(&settings).unwrap()),
-            monitor_url: settings.monitor_url.clone(),
-            local_library: LocalAudioLibrary::new(&settings.local_audio),
-            youtube_library: LocalAudioLibrary::new_youtube(&settings.youtube_audio),
-            local_player: Arc::new(LocalAudioPlayer::new(sender.clone())),
+            spotify: Arc::new(SpotifyClient::new_with_sender(
+                sender.clone(),
+                track_sleep.clone(),
+            )),
+            health: Arc::new(PipelineHealth::new(pipeline.clone())),
+            recovery: Arc::new(MonitorRecovery::new(pipeline.clone())),
+            watchdog: Arc::new(MonitorWatchdog::new(
+                &settings.monitor_watchdog,
+                pipeline.clone(),
+            )),
+            pipeline,
+            reloader,
+            http_address: settings.http_address().unwrap(),
+            http_tls: settings.http_tls(),
+            auth: Arc::new(ApiAuth::new(
+                settings.api_auth,
+                settings.api_tokens.clone(),
+                data_paths::device_tokens_file(),
+            )),
+            local_library,
+            youtube_library,
+            local_player: Arc::new(LocalAudioPlayer::new(
+                sender.clone(),
+                track_sleep.clone(),
+                play_order.clone(),
+            )),
             system_playlists: SystemPlaylistStore::new(data_paths::system_playlists_file()),
+            routines: RoutineStore::new(data_paths::routines_file()),
             audio_bridge,
+            fader: Arc::new(MusicFader::new(fade_gain, settings.fade_in.fade())),
             music_volume,
+            track_sleep,
+            play_order,
+            sound_detector,
+            ducking,
+            volume,
         }
     }
 
     pub async fn start(&mut self) {
         log::info!("Starting CareChordsServer!");
         self.start_gstreamer();
+        self.ducking.start(self.sound_detector.loudness_channel());
+        self.watchdog.start(self.sound_detector.loudness_channel());
+        self.reloader.start_watching();
 
         let playback = Arc::new(PlaybackController::new(
             self.local_library.clone(),
             self.youtube_library.clone(),
             self.local_player.clone(),
             self.system_playlists.clone(),
+            self.fader.clone(),
             self.music_volume.clone(),
+            self.track_sleep.clone(),
+            self.play_order.clone(),
+            self.volume.clone(),
+            self.watchdog.alarm_channel(),
+            self.recovery.disconnected_channel(),
         ));
         self.start_spotify(playback.clone());
-        start_http_server(playback, self.monitor_url.clone());
+        self.routines.start_scheduler(playback.clone());
+        start_http_server(
+            self.http_address,
+            self.http_tls.clone(),
+            HttpServices {
+                auth: self.auth.clone(),
+                playback,
+                routines: self.routines.clone(),
+                reloader: self.reloader.clone(),
+                sound_detector: self.sound_detector.clone(),
+                ducking: self.ducking.clone(),
+                health: self.health.clone(),
+                recovery: self.recovery.clone(),
+                watchdog: self.watchdog.clone(),
+            },
+        );
     }
 
     fn start_spotify(&self, playback: Arc<PlaybackController>) {
@@ -117,19 +226,24 @@ impl CareChordsServer {
 
         task::spawn(async move {
             while receiver.changed().await.is_ok() {
-                println!("{:?}", *receiver.borrow());
+                log::trace!("{:?}", *receiver.borrow());
             }
         });
     }
 
     fn start_gstreamer(&mut self) {
         log::info!("Starting GStreamer!");
-        let pipeline = self.pipeline.clone();
+        let pipeline_handle = self.pipeline.clone();
         let audio_bridge = self.audio_bridge.clone();
+        let sound_detector = self.sound_detector.clone();
+        let volume = self.volume.clone();
+        let health = self.health.clone();
+        let recovery = self.recovery.clone();
 
         tokio::spawn(async move {
             loop {
                 log::info!("Initializing GStreamer pipeline...");
+                let pipeline = pipeline_handle.current();
                 let bus = pipeline
                     .get_bus()
                     .expect("Pipeline without bus. Shouldn't happen!");
@@ -142,50 +256,120 @@ impl CareChordsServer {
                     .dynamic_cast::<AppSrc>()
                     .expect("Source element is not an AppSrc!");
                 audio_bridge.set_app_src(app_src);
+                volume.attach_mixer_pads(MixerPads {
+                    music: pipeline.spotify_mixer_pad.clone(),
+                    monitor: pipeline.monitor_mixer_pad.clone(),
+                    monitors: pipeline.monitor_pads.clone(),
+                });
 
                 // Start the pipeline
                 if let Err(e) = pipeline.set_state(gst::State::Playing) {
                     log::error!("Failed to set pipeline to Playing: {}", e);
+                    health.record_error("pipeline".to_string(), e.to_string(), None);
+                    health.pipeline_start_failed();
+                    metrics::PIPELINE_START_FAILURES.inc();
                     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
                     continue;
                 }
+                health.pipeline_started();
+                recovery.reset();
 
                 // Monitor the bus
-                handle_gst_bus_messages(bus, pipeline.gstreamer_pipeline.clone().into()).await;
-
-                // If we are here, the pipeline has stopped or failed.
-                log::warn!("GStreamer pipeline stopped. Restarting in 1 second...");
+                let stop = handle_gst_bus_messages(
+                    bus,
+                    pipeline.gstreamer_pipeline.clone().into(),
+                    &sound_detector,
+                    &health,
+                    &recovery,
+                )
+                .await;
+                let replaced = stop == PipelineStop::Replaced;
+                health.pipeline_stopped(replaced);
                 audio_bridge.clear_app_src();
 
                 // Ensure pipeline is stopped before restarting
                 let _ = pipeline.set_state(gst::State::Null);
 
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                if replaced {
+                    metrics::PIPELINE_REPLACEMENTS.inc();
+                } else {
+                    // If we are here, the pipeline has stopped or failed.
+                    metrics::PIPELINE_RESTARTS.inc();
+                    log::warn!("GStreamer pipeline stopped. Restarting in 1 second...");
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
             }
         });
     }
 }
 
-async fn handle_gst_bus_messages(bus: gst::Bus, pipeline: Element) {
+/// Why the bus of a pipeline stopped being handled
+#[derive(Debug, PartialEq)]
+enum PipelineStop {
+    /// Replaced on purpose by a pipeline built from new settings
+    Replaced,
+    /// Stopped by an error or the end of the stream
+    Failed,
+}
+
+async fn handle_gst_bus_messages(
+    bus: gst::Bus,
+    pipeline: Element,
+    sound_detector: &SoundDetector,
+    health: &PipelineHealth,
+    recovery: &Arc<MonitorRecovery>,
+) -> PipelineStop {
+    let mut stop = PipelineStop::Failed;
     for msg in bus.iter_timed(ClockTime::NONE) {
         match msg.view() {
             gst::MessageView::Eos(..) => {
                 log::error!("End of stream reached");
+                health.record_error(
+                    "pipeline".to_string(),
+                    "End of stream reached".to_string(),
+                    None,
+                );
                 break;
             }
             gst::MessageView::Error(err) => {
-                log::error!(
-                    "Error from {}: {}",
-                    err.src()
-                        .map(|s| s.path_string())
-                        .unwrap_or_else(|| "None".into()),
-                    err.error()
+                let source = err
+                    .src()
+                    .map(|s| s.path_string().to_string())
+                    .unwrap_or_else(|| "None".into());
+                log::error!("Error from {}: {}", source, err.error());
+                let monitor_error = recovery.handle_error(&source);
+                health.record_error(
+                    source,
+                    err.error().to_string(),
+                    err.debug().map(|debug| debug.to_string()),
                 );
-                break;
+                // A failing camera is reconnected on its own, the music keeps playing
+                if !monitor_error {
+                    break;
+                }
             }
             gst::MessageView::Warning(warn) => {
                 log::error!("{:?}", warn);
             }
+            gst::MessageView::Application(application)
+                if application
+                    .structure()
+                    .is_some_and(|s| s.name() == REPLACE_PIPELINE_MESSAGE) =>
+            {
+                log::info!("Replacing GStreamer pipeline");
+                stop = PipelineStop::Replaced;
+                break;
+            }
+            gst::MessageView::Element(element) => {
+                // Every camera branch measures its own loudness
+                if let Some((rms_db, peak_db)) = element.structure().and_then(parse_level_message)
+                    && let Some(monitor) = element
+                        .src()
+                        .and_then(|src| recovery.monitor_name(&src.path_string()))
+                {
+                    sound_detector.process_level(&monitor, rms_db, peak_db);
+                }
+            }
             // gst::MessageView::StreamStatus(s) => {log::info!("Received stream status: {:?}", s);}
             _ => (),
         }
@@ -195,4 +379,23 @@ async fn handle_gst_bus_messages(bus: gst::Bus, pipeline: Element) {
     if let Err(e) = pipeline.set_state(gst::State::Null) {
         log::error!("Failed to set pipeline state to Null: {}", e);
     }
+    stop
+}
+
+/// Extract the loudest channel's RMS and peak (in dB) from a `level` element message
+fn parse_level_message(structure: &gst::StructureRef) -> Option<(f64, f64)> {
+    if structure.name() != "level" {
+        return None;
+    }
+
+    let loudest = |field: &str| {
+        structure
+            .get::<gst::glib::ValueArray>(field)
+            .ok()?
+            .iter()
+            .filter_map(|value| value.get::<f64>().ok())
+            .reduce(f64::max)
+    };
+
+    Some((loudest("rms")?, loudest("peak")?))
 }
@@ -0,0 +1,312 @@
+use crate::app_settings::SoundDetectionSettings;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, watch};
+use tokio::time::Instant;
+
+/// How long the monitor has to be quiet again before an ongoing sound event ends
+const QUIET_HOLD: Duration = Duration::from_secs(2);
+
+/// Level reported before the first measurement of the monitor arrives
+pub const SILENCE_DB: f64 = -100.0;
+
+/// A camera that has not reported its loudness for this long no longer counts towards the
+/// loudness of the monitor, e.g. because it was disconnected
+const STALE_AFTER: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MonitorLoudness {
+    pub rms_db: f64,
+    pub peak_db: f64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SoundEventKind {
+    Started,
+    Ended,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SoundEvent {
+    pub kind: SoundEventKind,
+    /// Name of the camera that was loud
+    pub monitor: String,
+    /// Unix epoch (milliseconds) at which the monitor first exceeded the threshold
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub peak_db: f64,
+    pub threshold_db: f64,
+}
+
+/// Turns the loudness measurements of the cameras into sound events.
+///
+/// The `level` element in every camera branch posts its measurements on the pipeline bus, before
+/// the gain and mute of the camera, which are fed into `process_level`. Once a camera stays above
+/// the configured threshold for the configured duration a `Started` event is broadcast, followed
+/// by an `Ended` event when the camera has been quiet again for a little while. The loudness
+/// channel reports the loudest camera.
+pub struct SoundDetector {
+    settings: Mutex<SoundDetectionSettings>,
+    state: Mutex<HashMap<String, DetectorState>>,
+    loudness: Mutex<HashMap<String, (Instant, MonitorLoudness)>>,
+    loudness_sender: watch::Sender<MonitorLoudness>,
+    event_sender: broadcast::Sender<SoundEvent>,
+}
+
+#[derive(Default)]
+struct DetectorState {
+    loud_since: Option<(Instant, SystemTime)>,
+    quiet_since: Option<Instant>,
+    peak_db: f64,
+    active: bool,
+}
+
+impl SoundDetector {
+    pub fn new(settings: &SoundDetectionSettings) -> Self {
+        let (loudness_sender, _) = watch::channel(MonitorLoudness {
+            rms_db: SILENCE_DB,
+            peak_db: SILENCE_DB,
+        });
+        let (event_sender, _) = broadcast::channel(16);
+
+        Self {
+            settings: Mutex::new(settings.clone()),
+            state: Mutex::new(HashMap::new()),
+            loudness: Mutex::new(HashMap::new()),
+            loudness_sender,
+            event_sender,
+        }
+    }
+
+    pub fn update_settings(&self, settings: &SoundDetectionSettings) {
+        *self.settings.lock().unwrap() = settings.clone();
+    }
+
+    /// This channel emits the loudness of the loudest camera with every measurement
+    pub fn loudness_channel(&self) -> watch::Receiver<MonitorLoudness> {
+        self.loudness_sender.subscribe()
+    }
+
+    /// This channel emits sound events as they start and end
+    pub fn subscribe(&self) -> broadcast::Receiver<SoundEvent> {
+        self.event_sender.subscribe()
+    }
+
+    pub fn process_level(&self, monitor: &str, rms_db: f64, peak_db: f64) {
+        let now = Instant::now();
+        self.loudness_sender.send_replace(self.loudest_at(
+            now,
+            monitor,
+            MonitorLoudness { rms_db, peak_db },
+        ));
+        if let Some(event) = self.process_level_at(now, monitor, rms_db, peak_db) {
+            log::info!(
+                "Monitor {} sound {:?} (peak {:.1} dB, {} ms)",
+                event.monitor,
+                event.kind,
+                event.peak_db,
+                event.duration_ms
+            );
+            let _ = self.event_sender.send(event);
+        }
+    }
+
+    /// Record the loudness of a camera and return the loudest of the cameras that still report
+    fn loudest_at(
+        &self,
+        now: Instant,
+        monitor: &str,
+        loudness: MonitorLoudness,
+    ) -> MonitorLoudness {
+        let mut monitors = self.loudness.lock().unwrap();
+        monitors.insert(monitor.to_string(), (now, loudness));
+        monitors.retain(|_, (measured_at, _)| now.duration_since(*measured_at) < STALE_AFTER);
+        monitors.values().fold(
+            MonitorLoudness {
+                rms_db: SILENCE_DB,
+                peak_db: SILENCE_DB,
+            },
+            |loudest, (_, loudness)| MonitorLoudness {
+                rms_db: loudest.rms_db.max(loudness.rms_db),
+                peak_db: loudest.peak_db.max(loudness.peak_db),
+            },
+        )
+    }
+
+    fn process_level_at(
+        &self,
+        now: Instant,
+        monitor: &str,
+        rms_db: f64,
+        peak_db: f64,
+    ) -> Option<SoundEvent> {
+        let settings = self.settings.lock().unwrap().clone();
+        let min_duration = Duration::from_millis(settings.min_duration_ms);
+        let mut states = self.state.lock().unwrap();
+        let state = states.entry(monitor.to_string()).or_default();
+
+        if rms_db >= settings.threshold_db {
+            state.quiet_since = None;
+            let (loud_since, started_at) = match state.loud_since {
+                Some(loud_since) => loud_since,
+                None => {
+                    state.peak_db = peak_db;
+                    *state.loud_since.insert((now, SystemTime::now()))
+                }
+            };
+            state.peak_db = state.peak_db.max(peak_db);
+
+            if !state.active && now.duration_since(loud_since) >= min_duration {
+                state.active = true;
+                return Some(SoundEvent {
+                    kind: SoundEventKind::Started,
+                    monitor: monitor.to_string(),
+                    started_at: epoch_millis(started_at),
+                    duration_ms: now.duration_since(loud_since).as_millis() as u64,
+                    peak_db: state.peak_db,
+                    threshold_db: settings.threshold_db,
+                });
+            }
+            return None;
+        }
+
+        if !state.active {
+            state.loud_since = None;
+            return None;
+        }
+
+        let quiet_since = *state.quiet_since.get_or_insert(now);
+        if now.duration_since(quiet_since) < QUIET_HOLD {
+            return None;
+        }
+
+        let (loud_since, started_at) = state.loud_since.take()?;
+        let event = SoundEvent {
+            kind: SoundEventKind::Ended,
+            monitor: monitor.to_string(),
+            started_at: epoch_millis(started_at),
+            duration_ms: quiet_since.duration_since(loud_since).as_millis() as u64,
+            peak_db: state.peak_db,
+            threshold_db: settings.threshold_db,
+        };
+        *state = DetectorState::default();
+        Some(event)
+    }
+}
+
+fn epoch_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> SoundDetector {
+        SoundDetector::new(&SoundDetectionSettings {
+            threshold_db: -30.0,
+            min_duration_ms: 1000,
+        })
+    }
+
+    #[test]
+    fn short_noises_do_not_raise_events() {
+        let detector = detector();
+        let start = Instant::now();
+
+        assert!(
+            detector
+                .process_level_at(start, "nursery", -20.0, -10.0)
+                .is_none()
+        );
+        assert!(
+            detector
+                .process_level_at(start + Duration::from_millis(500), "nursery", -20.0, -10.0)
+                .is_none()
+        );
+        assert!(
+            detector
+                .process_level_at(start + Duration::from_millis(600), "nursery", -50.0, -40.0)
+                .is_none()
+        );
+        assert!(
+            detector
+                .process_level_at(start + Duration::from_millis(1200), "nursery", -20.0, -10.0)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn sustained_sound_starts_and_ends_an_event() {
+        let detector = detector();
+        let start = Instant::now();
+
+        assert!(
+            detector
+                .process_level_at(start, "nursery", -20.0, -12.0)
+                .is_none()
+        );
+        let started = detector
+            .process_level_at(start + Duration::from_millis(1000), "nursery", -20.0, -8.0)
+            .unwrap();
+        assert_eq!(started.kind, SoundEventKind::Started);
+        assert_eq!(started.monitor, "nursery");
+        assert_eq!(started.peak_db, -8.0);
+
+        let quiet = start + Duration::from_millis(1500);
+        assert!(
+            detector
+                .process_level_at(quiet, "nursery", -60.0, -50.0)
+                .is_none()
+        );
+        let ended = detector
+            .process_level_at(quiet + QUIET_HOLD, "nursery", -60.0, -50.0)
+            .unwrap();
+        assert_eq!(ended.kind, SoundEventKind::Ended);
+        assert_eq!(ended.duration_ms, 1500);
+    }
+
+    #[test]
+    fn cameras_are_detected_separately() {
+        let detector = detector();
+        let start = Instant::now();
+
+        assert!(
+            detector
+                .process_level_at(start, "nursery", -20.0, -10.0)
+                .is_none()
+        );
+        // A quiet camera neither ends nor interrupts the sound of another one
+        assert!(
+            detector
+                .process_level_at(start + Duration::from_millis(500), "bedroom", -60.0, -50.0)
+                .is_none()
+        );
+        let started = detector
+            .process_level_at(start + Duration::from_millis(1000), "nursery", -20.0, -10.0)
+            .unwrap();
+        assert_eq!(started.monitor, "nursery");
+
+        let nursery = MonitorLoudness {
+            rms_db: -20.0,
+            peak_db: -10.0,
+        };
+        let bedroom = MonitorLoudness {
+            rms_db: -60.0,
+            peak_db: -5.0,
+        };
+        detector.loudest_at(start, "nursery", nursery);
+        let loudest = detector.loudest_at(start + Duration::from_millis(100), "bedroom", bedroom);
+        assert_eq!(loudest.rms_db, -20.0);
+        assert_eq!(loudest.peak_db, -5.0);
+
+        // The nursery camera stopped reporting
+        let loudest = detector.loudest_at(start + STALE_AFTER, "bedroom", bedroom);
+        assert_eq!(loudest.rms_db, -60.0);
+    }
+}
@@ -1,6 +1,7 @@
 use futures::StreamExt;
 
 use crate::data_paths;
+use crate::music_timer::TrackSleepTimer;
 use crate::spotify_player::{PlayerCommand, SpotifyPlayer, SpotifyPlayerInfo};
 use crate::spotify_sink::SinkEvent;
 use anyhow::{Result, anyhow};
@@ -34,6 +35,7 @@ const PLAYLIST_METADATA_REQUEST_SPACING: Duration = Duration::from_millis(250);
 const PLAYLIST_ARTWORK_REQUEST_SPACING: Duration = Duration::from_millis(500);
 pub struct UnauthenticatedSpotifyClient {
     audio_sender: Option<SyncSender<SinkEvent>>,
+    track_sleep: Arc<TrackSleepTimer>,
 }
 
 pub struct SpotifyClient {
@@ -147,12 +149,19 @@ impl PlaylistMetadataCache {
 
 impl SpotifyClient {
     pub fn new() -> UnauthenticatedSpotifyClient {
-        UnauthenticatedSpotifyClient { audio_sender: None }
+        UnauthenticatedSpotifyClient {
+            audio_sender: None,
+            track_sleep: Arc::new(TrackSleepTimer::default()),
+        }
     }
 
-    pub fn new_with_sender(sender: SyncSender<SinkEvent>) -> UnauthenticatedSpotifyClient {
+    pub fn new_with_sender(
+        sender: SyncSender<SinkEvent>,
+        track_sleep: Arc<TrackSleepTimer>,
+    ) -> UnauthenticatedSpotifyClient {
         UnauthenticatedSpotifyClient {
             audio_sender: Some(sender),
+            track_sleep,
         }
     }
     /// This channel can push commands to the player
@@ -771,12 +780,14 @@ impl UnauthenticatedSpotifyClient {
         Ok(Self::from_authenticated_session(
             session,
             self.audio_sender.clone(),
+            self.track_sleep.clone(),
         ))
     }
 
     fn from_authenticated_session(
         session: Session,
         external_sender: Option<SyncSender<SinkEvent>>,
+        track_sleep: Arc<TrackSleepTimer>,
     ) -> SpotifyClient {
         let (sender, receiver) = if let Some(s) = external_sender {
             (s, None)
@@ -785,7 +796,7 @@ impl UnauthenticatedSpotifyClient {
             (s, Some(r))
         };
 
-        let player = SpotifyPlayer::new(session.clone(), sender);
+        let player = SpotifyPlayer::new(session.clone(), sender, track_sleep);
         let command_channel = player.command_channel();
         let info_channel = player.player_info_channel();
 
@@ -1,5 +1,9 @@
 use crate::data_paths;
+use crate::metrics;
+use crate::music_timer::{SleepTimerMode, TrackSleepTimer};
+use crate::play_order::RepeatMode;
 use crate::spotify_sink::{ChannelSink, SinkEvent};
+use crate::volume_control::VolumeLevels;
 use librespot_core::cache::Cache;
 use librespot_core::{Session, SessionConfig, SpotifyUri};
 use librespot_metadata::artist::ArtistRole;
@@ -22,6 +26,8 @@ use tokio::time::{Instant, sleep};
 
 const MIN_VALID_PLAYBACK_DURATION: Duration = Duration::from_secs(5);
 const MAX_CONSECUTIVE_PLAYBACK_FAILURES: usize = 3;
+const MAX_TRACK_HISTORY: usize = 100;
+const POSITION_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MusicMetadata {
@@ -30,6 +36,10 @@ pub struct MusicMetadata {
     pub artwork_url: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
 }
 
 #[derive(Clone, Debug)]
@@ -38,17 +48,36 @@ pub enum PlayerCommand {
     Play,
     Pause,
     Next,
+    Previous,
+    Seek(u32),
     Shuffle(bool),
+    Repeat(RepeatMode),
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct SpotifyPlayerInfo {
     pub status: SpotifyPlayerState,
     pub shuffle: bool,
+    pub repeat: RepeatMode,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<MusicMetadata>,
+    /// Playback position within the current track
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_ms: Option<u64>,
+    /// Remaining seconds of a timed sleep timer
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sleep_timer: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sleep_timer_mode: Option<SleepTimerMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<VolumeLevels>,
+    /// Set while the monitor watchdog reports that the baby monitor can no longer be heard
+    pub monitor_alarm: bool,
+    /// Set while a camera lost its connection and is being reconnected
+    pub monitor_disconnected: bool,
+    /// The player can step back within what it is playing, e.g. to an earlier track of a playlist
+    #[serde(skip)]
+    pub has_previous: bool,
 }
 
 impl SpotifyPlayerInfo {
@@ -56,8 +85,15 @@ impl SpotifyPlayerInfo {
         Self {
             status: SpotifyPlayerState::Stopped,
             shuffle: false,
+            repeat: RepeatMode::default(),
             metadata: None,
+            position_ms: None,
             sleep_timer: None,
+            sleep_timer_mode: None,
+            volume: None,
+            monitor_alarm: false,
+            monitor_disconnected: false,
+            has_previous: false,
         }
     }
 }
@@ -70,16 +106,23 @@ pub struct SpotifyPlayer {
     session: Session,
     state: SpotifyPlayerState,
     queue: VecDeque<SpotifyUri>,
+    history: Vec<SpotifyUri>,
     playlist_tracks: Vec<SpotifyUri>,
     player: Arc<Player>,
     shuffle: bool,
+    /// Whether the loaded playlist may start over, the repeat mode decides whether it does
     repeat: bool,
+    repeat_mode: RepeatMode,
     current_song: Option<MusicMetadata>,
+    position_ms: Option<u32>,
     volume: Arc<PlaybackVolume>,
     audio_sender: SyncSender<SinkEvent>,
     failed_skips: usize,
     current_track_uri: Option<SpotifyUri>,
     current_track_started_at: Option<Instant>,
+    track_sleep: Arc<TrackSleepTimer>,
+    /// The sleep timer paused playback after the last track of the queue
+    paused_at_end: bool,
 }
 
 #[derive(Clone, Debug, Serialize, PartialEq)]
@@ -127,7 +170,11 @@ impl VolumeGetter for ArcVolumeWrapper {
 }
 
 impl SpotifyPlayer {
-    pub fn new(session: Session, audio_sender: SyncSender<SinkEvent>) -> Self {
+    pub fn new(
+        session: Session,
+        audio_sender: SyncSender<SinkEvent>,
+        track_sleep: Arc<TrackSleepTimer>,
+    ) -> Self {
         let (sender, receiver) = channel::<PlayerCommand>(3);
         let volume = Arc::new(PlaybackVolume::new(0.1));
 
@@ -135,8 +182,15 @@ impl SpotifyPlayer {
         let info = SpotifyPlayerInfo {
             status: SpotifyPlayerState::Stopped,
             metadata: None,
+            position_ms: None,
             shuffle: false,
+            repeat: RepeatMode::default(),
             sleep_timer: None,
+            sleep_timer_mode: None,
+            volume: None,
+            monitor_alarm: false,
+            monitor_disconnected: false,
+            has_previous: false,
         };
 
         let (player_info_sender, player_info_receiver) = watch::channel(info);
@@ -148,17 +202,22 @@ impl SpotifyPlayer {
             player_info_sender,
             state: SpotifyPlayerState::Stopped,
             queue: VecDeque::new(),
+            history: Vec::new(),
             playlist_tracks: Vec::new(),
             session,
             player,
             shuffle: false,
             repeat: true,
+            repeat_mode: RepeatMode::default(),
             current_song: None,
+            position_ms: None,
             volume,
             audio_sender,
             failed_skips: 0,
             current_track_uri: None,
             current_track_started_at: None,
+            track_sleep,
+            paused_at_end: false,
         }
     }
 
@@ -171,6 +230,7 @@ impl SpotifyPlayer {
     /// channel, so the GStreamer bridge keeps consuming output transparently.
     async fn reconnect(&mut self) -> Option<UnboundedReceiver<PlayerEvent>> {
         log::warn!("Spotify session is invalid; attempting to reconnect");
+        metrics::SPOTIFY_RECONNECTS.inc();
 
         let cache = create_cache();
         let credentials = match cache.as_ref().and_then(|c| c.credentials()) {
@@ -219,8 +279,15 @@ impl SpotifyPlayer {
         let state = SpotifyPlayerInfo {
             status: self.state.clone(),
             metadata: self.current_song.clone(),
+            position_ms: self.position_ms.map(u64::from),
             shuffle: self.shuffle,
+            repeat: self.repeat_mode,
             sleep_timer: None,
+            sleep_timer_mode: None,
+            volume: None,
+            monitor_alarm: false,
+            monitor_disconnected: false,
+            has_previous: !self.history.is_empty(),
         };
 
         self.player_info_sender.send(state).unwrap();
@@ -266,9 +333,26 @@ impl SpotifyPlayer {
                                 self.play_next_song().await;
                             }
                         }
+                        PlayerCommand::Previous => {
+                            if self.ensure_session(&mut spotify_player_events).await {
+                                self.failed_skips = 0;
+                                self.play_previous_song();
+                            }
+                        }
+                        PlayerCommand::Seek(position_ms) => {
+                            if matches!(self.state, SpotifyPlayerState::Playing | SpotifyPlayerState::Paused) {
+                                log::info!("Seeking to {position_ms} ms");
+                                self.player.seek(position_ms);
+                            } else {
+                                // The controller rejects this, unless the track stopped meanwhile
+                                log::warn!("Ignoring seek to {position_ms} ms, nothing is playing");
+                            }
+                        }
                         PlayerCommand::Play => {
                             if self.ensure_session(&mut spotify_player_events).await {
-                                if let SpotifyPlayerState::Paused = self.state {
+                                if self.paused_at_end {
+                                    self.play_next_song().await;
+                                } else if let SpotifyPlayerState::Paused = self.state {
                                     self.player.play();
                                 }
                             }
@@ -278,6 +362,10 @@ impl SpotifyPlayer {
                             self.rebuild_queue();
                             self.emit_player_state().await;
                         }
+                        PlayerCommand::Repeat(repeat_mode) => {
+                            self.repeat_mode = repeat_mode;
+                            self.emit_player_state().await;
+                        }
                     }
                 }
 
@@ -285,14 +373,29 @@ impl SpotifyPlayer {
                 Some(event) = spotify_player_events.recv() => {
                     log::trace!("Received player event: {:?}", event);
                     match event {
-                        PlayerEvent::Playing{ track_id, .. } => {
+                        PlayerEvent::Playing{ track_id, position_ms, .. } => {
                             self.current_track_uri = Some(track_id);
                             self.current_track_started_at = Some(Instant::now());
                             self.failed_skips = 0;
-                            self.set_state(SpotifyPlayerState::Playing).await;
+                            self.position_ms = Some(position_ms);
+                            self.state = SpotifyPlayerState::Playing;
+                            self.emit_player_state().await;
+                        }
+                        PlayerEvent::Paused { position_ms, .. } => {
+                            self.position_ms = Some(position_ms);
+                            self.state = SpotifyPlayerState::Paused;
+                            self.emit_player_state().await;
+                        }
+                        PlayerEvent::Stopped { .. } => {
+                            self.position_ms = None;
+                            self.set_state(SpotifyPlayerState::Stopped).await;
+                        }
+                        PlayerEvent::PositionCorrection { position_ms, .. }
+                        | PlayerEvent::PositionChanged { position_ms, .. }
+                        | PlayerEvent::Seeked { position_ms, .. } => {
+                            self.position_ms = Some(position_ms);
+                            self.emit_player_state().await;
                         }
-                        PlayerEvent::Paused { .. } => self.set_state(SpotifyPlayerState::Paused).await,
-                        PlayerEvent::Stopped { .. } => self.set_state(SpotifyPlayerState::Stopped).await,
                         PlayerEvent::EndOfTrack { track_id, .. } => {
                             if self.track_ended_too_quickly(&track_id) {
                                 self.handle_track_load_failure(
@@ -302,7 +405,7 @@ impl SpotifyPlayer {
                                 ).await;
                             } else {
                                 self.failed_skips = 0;
-                                self.play_next_song().await;
+                                self.play_song_after_end_of_track().await;
                             }
                         }
                         PlayerEvent::Unavailable { track_id, .. } => {
@@ -315,15 +418,19 @@ impl SpotifyPlayer {
                         PlayerEvent::TrackChanged { audio_item} => {
                             // log::trace!("Track changed to {:?}", audio_item);
 
-                            let artist = match &audio_item.unique_fields {
-                                UniqueFields::Track { artists, .. } => {
-                                    artists.0.iter()
+                            let (artist, album) = match &audio_item.unique_fields {
+                                UniqueFields::Track { artists, album, .. } => {
+                                    let artist = artists.0.iter()
                                         .find(|a| a.role == ArtistRole::ARTIST_ROLE_MAIN_ARTIST)
                                         .or_else(|| artists.0.first())
                                         .map(|a| a.name.clone())
-                                        .unwrap_or_else(|| "Unknown Artist".to_string())
+                                        .unwrap_or_else(|| "Unknown Artist".to_string());
+                                    (artist, Some(album.clone()))
                                 },
-                                _ => "Unknown Artist".to_string(),
+                                UniqueFields::Episode { show_name, .. } => {
+                                    ("Unknown Artist".to_string(), Some(show_name.clone()))
+                                },
+                                _ => ("Unknown Artist".to_string(), None),
                             };
 
                             let metadata = MusicMetadata {
@@ -333,8 +440,11 @@ impl SpotifyPlayer {
                                     .map(|c| c.url.clone())
                                     .unwrap_or_else(|| "".to_string()),
                                 source: Some("spotify".to_string()),
+                                album,
+                                duration_ms: Some(audio_item.duration_ms as u64),
                             };
                             self.current_song = Some(metadata);
+                            self.position_ms = Some(0);
                             self.emit_player_state().await;
                         }
                         _ => {}
@@ -345,17 +455,81 @@ impl SpotifyPlayer {
     }
 
     async fn play_next_song(&mut self) {
-        if self.queue.is_empty() && self.repeat && !self.playlist_tracks.is_empty() {
+        self.load_next_song(true).await;
+    }
+
+    /// Continue with the next track after a track played to its end, unless the sleep timer
+    /// pauses playback here
+    async fn play_song_after_end_of_track(&mut self) {
+        let repeat_one = self.repeat_mode == RepeatMode::One;
+        let queue_ends = !repeat_one && self.queue.is_empty() && self.repeats_playlist();
+        if self.track_sleep.track_ended() || (queue_ends && self.track_sleep.queue_ended()) {
+            self.load_next_song(false).await;
+        } else if repeat_one && let Some(track_uri) = self.current_track_uri.clone() {
+            log::info!("Repeating Spotify track: {track_uri}");
+            self.current_track_started_at = None;
+            self.player.load(track_uri, true, 0);
+        } else {
+            self.play_next_song().await;
+        }
+    }
+
+    fn repeats_playlist(&self) -> bool {
+        self.repeat && self.repeat_mode != RepeatMode::Off && !self.playlist_tracks.is_empty()
+    }
+
+    async fn load_next_song(&mut self, start_playing: bool) {
+        self.paused_at_end = false;
+        if self.queue.is_empty() && self.repeats_playlist() {
             self.rebuild_queue();
         }
 
         if let Some(next_track_uri) = self.queue.pop_front() {
+            self.remember_current_track();
             log::info!("Loading Spotify track: {next_track_uri}");
             self.current_track_uri = Some(next_track_uri.clone());
             self.current_track_started_at = None;
-            self.player.load(next_track_uri, true, 0);
-        } else {
+            self.player.load(next_track_uri, start_playing, 0);
+        } else if start_playing {
             self.set_state(SpotifyPlayerState::Stopped).await;
+        } else {
+            // Resuming continues with whatever comes after this queue
+            self.paused_at_end = true;
+            self.set_state(SpotifyPlayerState::Paused).await;
+        }
+    }
+
+    /// Go back to the last track that was played, or restart the current track when there is no
+    /// history (yet).
+    fn play_previous_song(&mut self) {
+        let Some(current_track_uri) = self.current_track_uri.clone() else {
+            return;
+        };
+
+        let track_uri = match self.history.pop() {
+            Some(previous_track_uri) => {
+                self.queue.push_front(current_track_uri);
+                previous_track_uri
+            }
+            None => current_track_uri,
+        };
+
+        log::info!("Loading previous Spotify track: {track_uri}");
+        self.current_track_uri = Some(track_uri.clone());
+        self.current_track_started_at = None;
+        self.player.load(track_uri, true, 0);
+    }
+
+    /// Add the current track to the history, if it actually started playing
+    fn remember_current_track(&mut self) {
+        if self.current_track_started_at.is_none() {
+            return;
+        }
+        if let Some(track_uri) = self.current_track_uri.clone() {
+            self.history.push(track_uri);
+            if self.history.len() > MAX_TRACK_HISTORY {
+                self.history.remove(0);
+            }
         }
     }
 
@@ -377,6 +551,7 @@ impl SpotifyPlayer {
         reason: &str,
     ) {
         self.failed_skips += 1;
+        metrics::TRACK_LOAD_FAILURES.inc();
         log::warn!(
             "Spotify playback failure for {track_id}: {reason} (consecutive failures: {})",
             self.failed_skips
@@ -422,6 +597,9 @@ impl SpotifyPlayer {
 
     async fn load_ref_to_queue(&mut self, uri: &str, repeat: bool) {
         self.repeat = repeat;
+        // A new context starts without history
+        self.history.clear();
+        self.current_track_started_at = None;
         if uri.starts_with("spotify:playlist:") {
             self.load_playlist_to_queue(uri).await;
             return;
@@ -464,7 +642,7 @@ fn player_config() -> PlayerConfig {
         normalisation_knee_db: 0.0,
         local_file_directories: Vec::new(),
         ditherer: None,
-        position_update_interval: None,
+        position_update_interval: Some(POSITION_UPDATE_INTERVAL),
     }
 }
 
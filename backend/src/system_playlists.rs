@@ -1,10 +1,22 @@
+use crate::atomic_file;
+use crate::playlist_formats::ImportedItem;
 use anyhow::{Context, Result, anyhow};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Version of the system playlists file, add a migration to [`MIGRATIONS`] when it changes
+const SCHEMA_VERSION: u64 = 1;
+
+/// Upgrades of the system playlists file, the one at index `n` upgrades version `n` to `n + 1`
+const MIGRATIONS: [fn(Value) -> Result<Value>; SCHEMA_VERSION as usize] = [migrate_v0_list];
+
+const BACKUPS: usize = 3;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SystemPlaylist {
     pub id: String,
@@ -24,6 +36,15 @@ pub struct SystemPlaylistItem {
     pub title: String,
 }
 
+/// A track of a system playlist with its nested playlists expanded
+#[derive(Debug, Clone, Serialize)]
+pub struct NestedPlaylistItem {
+    #[serde(flatten)]
+    pub item: SystemPlaylistItem,
+    /// Names of the playlists the track is in, from the outermost to the one that holds it
+    pub playlists: Vec<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateSystemPlaylistRequest {
     pub name: String,
@@ -36,24 +57,94 @@ pub struct AddSystemPlaylistItemRequest {
     #[serde(rename = "ref")]
     pub reference: String,
     pub title: String,
+    /// The `updated_at` of the playlist the client edited, see [`PlaylistEditError::Conflict`]
+    #[serde(default)]
+    pub updated_at: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameSystemPlaylistRequest {
+    pub name: String,
+    #[serde(default)]
+    pub updated_at: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveSystemPlaylistItemRequest {
+    pub from_index: usize,
+    pub to_index: usize,
+    /// Required, the indexes only point at the right items in the version the client saw
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DuplicateSystemPlaylistRequest {
+    /// Defaults to the name of the original with " (copy)" appended
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Why a playlist could not be changed
+#[derive(Debug)]
+pub enum PlaylistEditError {
+    NotFound(String),
+    /// The playlist was changed since the client read it, i.e. its `updated_at` differs from the
+    /// one the client sent. Holds the current playlist.
+    Conflict(Box<SystemPlaylist>),
+}
+
+impl fmt::Display for PlaylistEditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaylistEditError::NotFound(id) => write!(f, "Unknown system playlist: {id}"),
+            PlaylistEditError::Conflict(playlist) => write!(
+                f,
+                "System playlist {} was changed by someone else",
+                playlist.id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlaylistEditError {}
+
+#[derive(Deserialize)]
+struct SystemPlaylistsFile {
+    version: u64,
+    playlists: Vec<SystemPlaylist>,
 }
 
 #[derive(Clone)]
 pub struct SystemPlaylistStore {
     path: PathBuf,
     playlists: Arc<Mutex<Vec<SystemPlaylist>>>,
+    /// Why the file could not be read, it is left alone until it is fixed by hand
+    load_error: Option<String>,
 }
 
 impl SystemPlaylistStore {
     pub fn new(path: PathBuf) -> Self {
-        let playlists = read_playlists(&path).unwrap_or_else(|e| {
-            log::warn!("Failed to load system playlists: {e}");
-            Vec::new()
-        });
+        let (playlists, load_error) = match read_playlists(&path) {
+            Ok(playlists) => (playlists, None),
+            Err(e) => {
+                let backups = atomic_file::backups(&path, BACKUPS);
+                log::error!(
+                    "Failed to load system playlists, changes will not be saved until {} is fixed or restored from a backup ({}): {e:#}",
+                    path.display(),
+                    backups
+                        .iter()
+                        .map(|backup| backup.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                (Vec::new(), Some(format!("{e:#}")))
+            }
+        };
 
         Self {
             path,
             playlists: Arc::new(Mutex::new(playlists)),
+            load_error,
         }
     }
 
@@ -71,11 +162,26 @@ impl SystemPlaylistStore {
     }
 
     pub fn create(&self, name: String) -> Result<SystemPlaylist> {
+        self.import(name, Vec::new())
+    }
+
+    /// Create a playlist with the items of an imported playlist file
+    pub fn import(&self, name: String, items: Vec<ImportedItem>) -> Result<SystemPlaylist> {
+        self.ensure_writable()?;
         let now = now_epoch_secs();
         let playlist = SystemPlaylist {
             id: format!("playlist-{now}-{}", rand::random::<u32>()),
             name,
-            items: Vec::new(),
+            items: items
+                .into_iter()
+                .map(|item| SystemPlaylistItem {
+                    id: format!("item-{now}-{}", rand::random::<u32>()),
+                    source: item.source,
+                    kind: item.kind,
+                    reference: item.reference,
+                    title: item.title,
+                })
+                .collect(),
             created_at: now,
             updated_at: now,
         };
@@ -89,49 +195,302 @@ impl SystemPlaylistStore {
         Ok(playlist)
     }
 
+    /// The tracks of a playlist in play order, with the system playlists in it expanded
+    pub fn flatten(&self, playlist_id: &str) -> Result<Vec<NestedPlaylistItem>> {
+        let playlists = self.playlists.lock().unwrap();
+        flatten_playlist(&playlists, playlist_id)
+    }
+
     pub fn add_item(
         &self,
         playlist_id: &str,
         item: AddSystemPlaylistItemRequest,
     ) -> Result<SystemPlaylist> {
+        if let Some(nested_id) = item.reference.strip_prefix("system:playlist:") {
+            let playlists = self.playlists.lock().unwrap();
+            if !playlists.iter().any(|playlist| playlist.id == nested_id) {
+                anyhow::bail!("Unknown system playlist: {nested_id}");
+            }
+            if contains_playlist(&playlists, nested_id, playlist_id, &mut Vec::new()) {
+                anyhow::bail!("A system playlist cannot contain itself");
+            }
+        }
+
+        self.edit(playlist_id, item.updated_at, |playlist| {
+            playlist.items.push(SystemPlaylistItem {
+                id: format!("item-{}-{}", now_epoch_secs(), rand::random::<u32>()),
+                source: item.source,
+                kind: item.kind,
+                reference: item.reference,
+                title: item.title,
+            });
+            Ok(())
+        })
+    }
+
+    pub fn rename(
+        &self,
+        playlist_id: &str,
+        req: RenameSystemPlaylistRequest,
+    ) -> Result<SystemPlaylist> {
+        let name = req.name.trim().to_string();
+        if name.is_empty() {
+            anyhow::bail!("System playlist name must not be empty");
+        }
+        self.edit(playlist_id, req.updated_at, |playlist| {
+            playlist.name = name;
+            Ok(())
+        })
+    }
+
+    pub fn remove_item(
+        &self,
+        playlist_id: &str,
+        item_id: &str,
+        updated_at: u64,
+    ) -> Result<SystemPlaylist> {
+        self.edit(playlist_id, Some(updated_at), |playlist| {
+            let index = playlist
+                .items
+                .iter()
+                .position(|item| item.id == item_id)
+                .ok_or_else(|| anyhow!("Unknown system playlist item: {item_id}"))?;
+            playlist.items.remove(index);
+            Ok(())
+        })
+    }
+
+    pub fn move_item(
+        &self,
+        playlist_id: &str,
+        req: MoveSystemPlaylistItemRequest,
+    ) -> Result<SystemPlaylist> {
+        self.edit(playlist_id, Some(req.updated_at), |playlist| {
+            if req.from_index >= playlist.items.len() || req.to_index >= playlist.items.len() {
+                anyhow::bail!("System playlist move index out of bounds");
+            }
+            let item = playlist.items.remove(req.from_index);
+            playlist.items.insert(req.to_index, item);
+            Ok(())
+        })
+    }
+
+    pub fn duplicate(
+        &self,
+        playlist_id: &str,
+        req: DuplicateSystemPlaylistRequest,
+    ) -> Result<SystemPlaylist> {
+        self.ensure_writable()?;
+        let mut playlists = self.playlists.lock().unwrap();
+        let original = playlists
+            .iter()
+            .find(|playlist| playlist.id == playlist_id)
+            .ok_or_else(|| PlaylistEditError::NotFound(playlist_id.to_string()))?;
+
+        let now = now_epoch_secs();
+        let copy = SystemPlaylist {
+            id: format!("playlist-{now}-{}", rand::random::<u32>()),
+            name: req
+                .name
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| format!("{} (copy)", original.name)),
+            items: original
+                .items
+                .iter()
+                .map(|item| SystemPlaylistItem {
+                    id: format!("item-{now}-{}", rand::random::<u32>()),
+                    ..item.clone()
+                })
+                .collect(),
+            created_at: now,
+            updated_at: now,
+        };
+        playlists.push(copy.clone());
+        self.persist_locked(&playlists)?;
+        Ok(copy)
+    }
+
+    pub fn delete(&self, playlist_id: &str, updated_at: u64) -> Result<()> {
+        self.ensure_writable()?;
+        let mut playlists = self.playlists.lock().unwrap();
+        let index = playlists
+            .iter()
+            .position(|playlist| playlist.id == playlist_id)
+            .ok_or_else(|| PlaylistEditError::NotFound(playlist_id.to_string()))?;
+        check_updated_at(&playlists[index], Some(updated_at))?;
+        playlists.remove(index);
+        self.persist_locked(&playlists)
+    }
+
+    /// Change a playlist, if the client saw its latest version
+    fn edit(
+        &self,
+        playlist_id: &str,
+        updated_at: Option<u64>,
+        change: impl FnOnce(&mut SystemPlaylist) -> Result<()>,
+    ) -> Result<SystemPlaylist> {
+        self.ensure_writable()?;
         let mut playlists = self.playlists.lock().unwrap();
         let playlist = playlists
             .iter_mut()
             .find(|playlist| playlist.id == playlist_id)
-            .ok_or_else(|| anyhow!("Unknown system playlist: {playlist_id}"))?;
-        playlist.items.push(SystemPlaylistItem {
-            id: format!("item-{}-{}", now_epoch_secs(), rand::random::<u32>()),
-            source: item.source,
-            kind: item.kind,
-            reference: item.reference,
-            title: item.title,
-        });
-        playlist.updated_at = now_epoch_secs();
-        let updated = playlist.clone();
+            .ok_or_else(|| PlaylistEditError::NotFound(playlist_id.to_string()))?;
+        check_updated_at(playlist, updated_at)?;
+
+        let mut edited = playlist.clone();
+        change(&mut edited)?;
+        // Every edit has to be visible as a new version, also within the same second
+        edited.updated_at = now_epoch_secs().max(playlist.updated_at + 1);
+        *playlist = edited.clone();
         self.persist_locked(&playlists)?;
-        Ok(updated)
+        Ok(edited)
+    }
+
+    /// Never overwrite a file that could not be read, the playlists in it would be lost
+    fn ensure_writable(&self) -> Result<()> {
+        match &self.load_error {
+            Some(e) => Err(anyhow!(
+                "System playlists are read-only because {} could not be loaded: {e}",
+                self.path.display()
+            )),
+            None => Ok(()),
+        }
     }
 
     fn persist_locked(&self, playlists: &[SystemPlaylist]) -> Result<()> {
-        if let Some(parent) = self.path.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        self.ensure_writable()?;
+        let json = serde_json::to_vec_pretty(&json!({
+            "version": SCHEMA_VERSION,
+            "playlists": playlists,
+        }))?;
+        atomic_file::write_with_backups(&self.path, &json, BACKUPS)
+    }
+}
+
+fn flatten_playlist(
+    playlists: &[SystemPlaylist],
+    playlist_id: &str,
+) -> Result<Vec<NestedPlaylistItem>> {
+    let playlist = find_playlist(playlists, playlist_id)
+        .ok_or_else(|| PlaylistEditError::NotFound(playlist_id.to_string()))?;
+    let mut items = Vec::new();
+    flatten_into(playlists, playlist, &mut Vec::new(), &mut items);
+    Ok(items)
+}
+
+/// Expand a playlist depth first. `path` holds the playlists being expanded, a playlist that is
+/// already on it would repeat forever and is skipped.
+fn flatten_into<'a>(
+    playlists: &'a [SystemPlaylist],
+    playlist: &'a SystemPlaylist,
+    path: &mut Vec<&'a SystemPlaylist>,
+    out: &mut Vec<NestedPlaylistItem>,
+) {
+    path.push(playlist);
+    for item in &playlist.items {
+        let Some(nested_id) = item.reference.strip_prefix("system:playlist:") else {
+            out.push(NestedPlaylistItem {
+                item: item.clone(),
+                playlists: path.iter().map(|playlist| playlist.name.clone()).collect(),
+            });
+            continue;
+        };
+
+        if path.iter().any(|playlist| playlist.id == nested_id) {
+            log::warn!(
+                "Skipping system playlist {nested_id} in {}, it contains itself",
+                playlist.name
+            );
+        } else if let Some(nested) = find_playlist(playlists, nested_id) {
+            flatten_into(playlists, nested, path, out);
+        } else {
+            log::warn!(
+                "Skipping unknown system playlist {nested_id} in {}",
+                playlist.name
+            );
+        }
+    }
+    path.pop();
+}
+
+/// Whether playlist `id` is `target` or holds it, directly or through other playlists
+fn contains_playlist<'a>(
+    playlists: &'a [SystemPlaylist],
+    id: &'a str,
+    target: &str,
+    visited: &mut Vec<&'a str>,
+) -> bool {
+    if id == target {
+        return true;
+    }
+    if visited.contains(&id) {
+        return false;
+    }
+    visited.push(id);
+
+    find_playlist(playlists, id).is_some_and(|playlist| {
+        playlist.items.iter().any(|item| {
+            item.reference
+                .strip_prefix("system:playlist:")
+                .is_some_and(|nested_id| contains_playlist(playlists, nested_id, target, visited))
+        })
+    })
+}
+
+fn find_playlist<'a>(playlists: &'a [SystemPlaylist], id: &str) -> Option<&'a SystemPlaylist> {
+    playlists.iter().find(|playlist| playlist.id == id)
+}
+
+fn check_updated_at(playlist: &SystemPlaylist, updated_at: Option<u64>) -> Result<()> {
+    match updated_at {
+        Some(updated_at) if updated_at != playlist.updated_at => {
+            Err(PlaylistEditError::Conflict(Box::new(playlist.clone())).into())
         }
-        let json = serde_json::to_vec_pretty(playlists)?;
-        fs::write(&self.path, json)
-            .with_context(|| format!("Failed to write {}", self.path.display()))?;
-        Ok(())
+        _ => Ok(()),
     }
 }
 
-fn read_playlists(path: &PathBuf) -> Result<Vec<SystemPlaylist>> {
+fn read_playlists(path: &Path) -> Result<Vec<SystemPlaylist>> {
     if !path.exists() {
         return Ok(Vec::new());
     }
     let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
-    let playlists = serde_json::from_slice(&bytes)
+    let document = serde_json::from_slice(&bytes)
         .with_context(|| format!("Failed to parse {}", path.display()))?;
-    Ok(playlists)
+    parse_playlists(document).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn parse_playlists(mut document: Value) -> Result<Vec<SystemPlaylist>> {
+    let version = match &document {
+        Value::Array(_) => 0,
+        _ => document
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow!("Missing schema version"))?,
+    };
+    if version > SCHEMA_VERSION {
+        anyhow::bail!(
+            "Schema version {version} is newer than the supported version {SCHEMA_VERSION}"
+        );
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        document = migration(document)?;
+    }
+    let file: SystemPlaylistsFile = serde_json::from_value(document)?;
+    if file.version != SCHEMA_VERSION {
+        anyhow::bail!(
+            "Migrated to schema version {} instead of {SCHEMA_VERSION}",
+            file.version
+        );
+    }
+    Ok(file.playlists)
+}
+
+/// Before the schema version the file was a bare list of playlists
+fn migrate_v0_list(document: Value) -> Result<Value> {
+    Ok(json!({ "version": 1, "playlists": document }))
 }
 
 fn now_epoch_secs() -> u64 {
@@ -140,3 +499,158 @@ fn now_epoch_secs() -> u64 {
         .unwrap_or_default()
         .as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_the_unversioned_list_of_playlists() {
+        let playlists = parse_playlists(json!([{
+            "id": "playlist-1",
+            "name": "Bedtime",
+            "items": [],
+            "created_at": 1,
+            "updated_at": 2,
+        }]))
+        .unwrap();
+
+        assert_eq!(playlists.len(), 1);
+        assert_eq!(playlists[0].name, "Bedtime");
+    }
+
+    fn playlist(id: &str, references: &[&str]) -> SystemPlaylist {
+        SystemPlaylist {
+            id: id.to_string(),
+            name: id.to_string(),
+            items: references
+                .iter()
+                .map(|reference| SystemPlaylistItem {
+                    id: format!("item-{reference}"),
+                    source: "system".to_string(),
+                    kind: "file".to_string(),
+                    reference: reference.to_string(),
+                    title: reference.to_string(),
+                })
+                .collect(),
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn expands_nested_playlists_and_skips_cycles() {
+        let playlists = vec![
+            playlist(
+                "bedtime",
+                &["system:playlist:lullabies", "system:playlist:noise"],
+            ),
+            playlist("lullabies", &["local:file:root:0/brahms.ogg"]),
+            playlist("noise", &["spotify:track:rain", "system:playlist:bedtime"]),
+        ];
+
+        let items = flatten_playlist(&playlists, "bedtime").unwrap();
+        let references = items
+            .iter()
+            .map(|nested| nested.item.reference.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            references,
+            ["local:file:root:0/brahms.ogg", "spotify:track:rain"]
+        );
+        assert_eq!(items[1].playlists, ["bedtime", "noise"]);
+
+        assert!(contains_playlist(
+            &playlists,
+            "noise",
+            "lullabies",
+            &mut Vec::new()
+        ));
+        assert!(!contains_playlist(
+            &playlists,
+            "lullabies",
+            "noise",
+            &mut Vec::new()
+        ));
+    }
+
+    fn temp_store() -> (PathBuf, SystemPlaylistStore) {
+        let dir =
+            std::env::temp_dir().join(format!("carechords-playlists-{}", rand::random::<u32>()));
+        let store = SystemPlaylistStore::new(dir.join("system_playlists.json"));
+        (dir, store)
+    }
+
+    fn is_conflict(result: Result<impl fmt::Debug>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<PlaylistEditError>(),
+            Some(PlaylistEditError::Conflict(_))
+        )
+    }
+
+    #[test]
+    fn rejects_edits_of_an_outdated_playlist() {
+        let (dir, store) = temp_store();
+        let created = store
+            .import(
+                "Bedtime".to_string(),
+                ["brahms.ogg", "rain.ogg"]
+                    .iter()
+                    .map(|reference| ImportedItem {
+                        source: "local".to_string(),
+                        kind: "file".to_string(),
+                        reference: reference.to_string(),
+                        title: reference.to_string(),
+                    })
+                    .collect(),
+            )
+            .unwrap();
+        let renamed = store
+            .rename(
+                &created.id,
+                RenameSystemPlaylistRequest {
+                    name: "Lullabies".to_string(),
+                    updated_at: Some(created.updated_at),
+                },
+            )
+            .unwrap();
+        assert!(renamed.updated_at > created.updated_at);
+        // Reordering an unknown version would move whatever is at the indexes now
+        assert!(
+            serde_json::from_value::<MoveSystemPlaylistItemRequest>(
+                json!({ "from_index": 0, "to_index": 1 })
+            )
+            .is_err()
+        );
+
+        let stale = created.updated_at;
+        let move_item = |updated_at| MoveSystemPlaylistItemRequest {
+            from_index: 0,
+            to_index: 1,
+            updated_at,
+        };
+        assert!(is_conflict(store.move_item(&created.id, move_item(stale))));
+        assert!(is_conflict(store.remove_item(
+            &created.id,
+            &created.items[0].id,
+            stale
+        )));
+        assert!(is_conflict(store.delete(&created.id, stale)));
+        assert_eq!(store.get(&created.id).unwrap().items.len(), 2);
+
+        let moved = store
+            .move_item(&created.id, move_item(renamed.updated_at))
+            .unwrap();
+        assert_eq!(moved.items[1].id, created.items[0].id);
+        store.delete(&created.id, moved.updated_at).unwrap();
+        assert!(store.get(&created.id).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_files_of_a_newer_schema() {
+        let document = json!({ "version": SCHEMA_VERSION + 1, "playlists": [] });
+        assert!(parse_playlists(document).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::app_settings::MonitorSettings;
+use crate::atomic_file;
+use crate::music_timer::MusicVolume;
+use anyhow::{Context, Result};
+use gstreamer::Pad;
+use gstreamer::prelude::ObjectExt;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct VolumeLevels {
+    #[serde(default = "unity_gain")]
+    pub master: f64,
+    #[serde(default = "unity_gain")]
+    pub music: f64,
+    #[serde(default = "unity_gain")]
+    pub monitor: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VolumeRequest {
+    pub master: Option<f64>,
+    pub music: Option<f64>,
+    pub monitor: Option<f64>,
+}
+
+/// A single monitor camera in the monitor mix
+#[derive(Debug, Clone, Serialize)]
+pub struct MonitorChannel {
+    pub name: String,
+    pub gain: f64,
+    pub muted: bool,
+    /// When any monitor is soloed, only that monitor is audible
+    pub soloed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MonitorChannelRequest {
+    pub gain: Option<f64>,
+    pub muted: Option<bool>,
+}
+
+/// The audio mixer pads of the current pipeline that carry the music and the monitor audio
+pub struct MixerPads {
+    pub music: Pad,
+    pub monitor: Pad,
+    /// The monitor mixer pad of each monitor, by monitor name
+    pub monitors: Vec<(String, Pad)>,
+}
+
+/// Owns the master, music and monitor volume levels shared by all clients.
+///
+/// The music level is applied by the `AudioBridge` through `music_gain`, the monitor and master
+/// levels are set on the audio mixer pads of the running pipeline. Levels are persisted so they
+/// survive a restart of the service. The gain and mute state of the individual monitors start
+/// from the settings on every start, and are kept when the settings are reloaded.
+pub struct VolumeControl {
+    path: PathBuf,
+    levels: Mutex<VolumeLevels>,
+    monitor_channels: Mutex<Vec<MonitorChannel>>,
+    music_gain: Arc<MusicVolume>,
+    mixer_pads: Mutex<Option<MixerPads>>,
+}
+
+impl Default for VolumeLevels {
+    fn default() -> Self {
+        Self {
+            master: unity_gain(),
+            music: unity_gain(),
+            monitor: unity_gain(),
+        }
+    }
+}
+
+impl VolumeControl {
+    pub fn new(path: PathBuf, monitors: &[MonitorSettings]) -> Self {
+        let levels = read_levels(&path).unwrap_or_else(|e| {
+            log::warn!("Failed to load volume levels: {e}");
+            VolumeLevels::default()
+        });
+
+        Self {
+            path,
+            music_gain: Arc::new(MusicVolume::new(levels.music)),
+            levels: Mutex::new(levels),
+            monitor_channels: Mutex::new(
+                monitors
+                    .iter()
+                    .map(|monitor| MonitorChannel {
+                        name: monitor.name.clone(),
+                        gain: monitor.gain,
+                        muted: monitor.muted,
+                        soloed: false,
+                    })
+                    .collect(),
+            ),
+            mixer_pads: Mutex::new(None),
+        }
+    }
+
+    pub fn levels(&self) -> VolumeLevels {
+        *self.levels.lock().unwrap()
+    }
+
+    /// The gain the `AudioBridge` applies to the music samples
+    pub fn music_gain(&self) -> Arc<MusicVolume> {
+        self.music_gain.clone()
+    }
+
+    pub fn monitor_channels(&self) -> Vec<MonitorChannel> {
+        self.monitor_channels.lock().unwrap().clone()
+    }
+
+    /// Follow the monitors of new settings. A camera that is still there keeps its runtime gain,
+    /// mute and solo state, unless its gain or mute setting itself changed.
+    pub fn set_monitors(&self, previous: &[MonitorSettings], monitors: &[MonitorSettings]) {
+        let mut channels = self.monitor_channels.lock().unwrap();
+        *channels = monitors
+            .iter()
+            .map(|monitor| {
+                let existing = channels.iter().find(|channel| channel.name == monitor.name);
+                let configured = previous
+                    .iter()
+                    .find(|previous| previous.name == monitor.name);
+                let gain = match (existing, configured) {
+                    (Some(channel), Some(configured)) if configured.gain == monitor.gain => {
+                        channel.gain
+                    }
+                    _ => monitor.gain,
+                };
+                let muted = match (existing, configured) {
+                    (Some(channel), Some(configured)) if configured.muted == monitor.muted => {
+                        channel.muted
+                    }
+                    _ => monitor.muted,
+                };
+                MonitorChannel {
+                    name: monitor.name.clone(),
+                    gain,
+                    muted,
+                    soloed: existing.is_some_and(|channel| channel.soloed),
+                }
+            })
+            .collect();
+    }
+
+    /// Apply the current levels to the mixer pads of a (re)built pipeline
+    pub fn attach_mixer_pads(&self, pads: MixerPads) {
+        let levels = self.levels();
+        apply_pad_levels(&pads, &levels);
+        apply_monitor_channels(&pads, &self.monitor_channels.lock().unwrap());
+        *self.mixer_pads.lock().unwrap() = Some(pads);
+    }
+
+    pub fn update_monitor_channel(
+        &self,
+        name: &str,
+        req: MonitorChannelRequest,
+    ) -> Result<MonitorChannel> {
+        let mut channels = self.monitor_channels.lock().unwrap();
+        let channel = channels
+            .iter_mut()
+            .find(|channel| channel.name == name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown monitor: {}", name))?;
+
+        if let Some(gain) = req.gain {
+            if !(0.0..=4.0).contains(&gain) {
+                anyhow::bail!("Monitor gain must be between 0.0 and 4.0");
+            }
+            channel.gain = gain;
+        }
+        if let Some(muted) = req.muted {
+            channel.muted = muted;
+        }
+        let updated = channel.clone();
+
+        if let Some(pads) = self.mixer_pads.lock().unwrap().as_ref() {
+            apply_monitor_channels(pads, &channels);
+        }
+        Ok(updated)
+    }
+
+    /// Only let the given monitor through, or all monitors again when no name is given
+    pub fn solo_monitor_channel(&self, name: Option<&str>) -> Result<Vec<MonitorChannel>> {
+        let mut channels = self.monitor_channels.lock().unwrap();
+        if let Some(name) = name
+            && !channels.iter().any(|channel| channel.name == name)
+        {
+            anyhow::bail!("Unknown monitor: {}", name);
+        }
+
+        for channel in channels.iter_mut() {
+            channel.soloed = Some(channel.name.as_str()) == name;
+        }
+
+        if let Some(pads) = self.mixer_pads.lock().unwrap().as_ref() {
+            apply_monitor_channels(pads, &channels);
+        }
+        Ok(channels.clone())
+    }
+
+    pub fn update(&self, req: VolumeRequest) -> Result<VolumeLevels> {
+        let mut levels = self.levels.lock().unwrap();
+        let mut updated = *levels;
+        if let Some(master) = req.master {
+            updated.master = validate_level("master", master)?;
+        }
+        if let Some(music) = req.music {
+            updated.music = validate_level("music", music)?;
+        }
+        if let Some(monitor) = req.monitor {
+            updated.monitor = validate_level("monitor", monitor)?;
+        }
+
+        // Saved first, so a level that is rejected by a failed write is never audible
+        self.persist(&updated)?;
+
+        self.music_gain.set_volume(updated.music);
+        if let Some(pads) = self.mixer_pads.lock().unwrap().as_ref() {
+            apply_pad_levels(pads, &updated);
+        }
+        *levels = updated;
+        Ok(updated)
+    }
+
+    fn persist(&self, levels: &VolumeLevels) -> Result<()> {
+        let json = serde_json::to_vec_pretty(levels)?;
+        atomic_file::write_with_backups(&self.path, &json, 0)
+    }
+}
+
+fn apply_pad_levels(pads: &MixerPads, levels: &VolumeLevels) {
+    pads.music.set_property("volume", levels.master);
+    pads.monitor
+        .set_property("volume", levels.monitor * levels.master);
+}
+
+fn apply_monitor_channels(pads: &MixerPads, channels: &[MonitorChannel]) {
+    let solo = channels.iter().any(|channel| channel.soloed);
+    for (name, pad) in &pads.monitors {
+        if let Some(channel) = channels.iter().find(|channel| &channel.name == name) {
+            pad.set_property("volume", channel.gain);
+            pad.set_property("mute", is_silenced(channel, solo));
+        }
+    }
+}
+
+fn is_silenced(channel: &MonitorChannel, solo: bool) -> bool {
+    channel.muted || (solo && !channel.soloed)
+}
+
+fn validate_level(name: &str, level: f64) -> Result<f64> {
+    if !(0.0..=1.0).contains(&level) {
+        anyhow::bail!("{name} volume must be between 0.0 and 1.0");
+    }
+    Ok(level)
+}
+
+fn read_levels(path: &PathBuf) -> Result<VolumeLevels> {
+    if !path.exists() {
+        return Ok(VolumeLevels::default());
+    }
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let levels: VolumeLevels = serde_json::from_slice(&bytes)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    // An edited or damaged file must not set the pipeline to an out of range volume
+    Ok(VolumeLevels {
+        master: validate_level("master", levels.master)?,
+        music: validate_level("music", levels.music)?,
+        monitor: validate_level("monitor", levels.monitor)?,
+    })
+}
+
+fn unity_gain() -> f64 {
+    1.0
+}
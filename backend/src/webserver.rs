@@ -1,20 +1,82 @@
+use crate::api_auth::{ApiAuth, PairRequest};
+use crate::app_settings::{DuckingSettings, MonitorSettings};
+use crate::ducking::DuckingController;
+use crate::metrics::{self, MetricsWriter};
+use crate::monitor_watchdog::MonitorWatchdog;
+use crate::music_timer::{Fade, FadeCurve, SleepTimerMode};
+use crate::pipeline::monitor_recovery::MonitorConnection;
+use crate::pipeline::monitor_recovery::MonitorRecovery;
+use crate::pipeline::pipeline_health::PipelineHealth;
+use crate::play_order::RepeatMode;
 use crate::playback_controller::{
     LegacyPlaylistRequest, PlayRefRequest, PlaybackController, QueueItemRequest,
     ReorderQueueRequest,
 };
-use crate::system_playlists::{AddSystemPlaylistItemRequest, CreateSystemPlaylistRequest};
+use crate::playlist_formats::{self, PlaylistFormat, PlaylistLibraries};
+use crate::routines::{RoutineRequest, RoutineStore};
+use crate::settings_reload::SettingsReloader;
+use crate::sound_detector::SoundDetector;
+use crate::system_playlists::{
+    AddSystemPlaylistItemRequest, CreateSystemPlaylistRequest, DuplicateSystemPlaylistRequest,
+    MoveSystemPlaylistItemRequest, PlaylistEditError, RenameSystemPlaylistRequest,
+};
+use crate::volume_control::{MonitorChannelRequest, VolumeRequest};
 use futures_util::StreamExt;
 use serde::Deserialize;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::watch;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
 use warp::http::{Response, StatusCode, header};
 use warp::hyper::Body;
 use warp::{Filter, Rejection, Reply};
 
+/// Largest playlist file accepted by the import
+const PLAYLIST_IMPORT_LIMIT: u64 = 4 * 1024 * 1024;
+
 #[derive(Deserialize)]
 struct SleepTimerRequest {
+    #[serde(default)]
+    mode: SleepRequestMode,
+    /// Seconds until the music pauses, 0 cancels the sleep timer
+    #[serde(default)]
     timer: u32,
+    /// Tracks to play before pausing in the `tracks` mode
+    tracks: Option<u32>,
+    /// Seconds over which the music fades out before the timer elapses
+    fade_s: Option<u32>,
+    fade_curve: Option<FadeCurve>,
+    /// Fade out over the whole duration of the timer
+    #[serde(default)]
+    fade_whole_timer: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum SleepRequestMode {
+    #[default]
+    Time,
+    EndOfTrack,
+    Tracks,
+    EndOfQueue,
+}
+
+impl SleepTimerRequest {
+    fn fade(&self) -> Fade {
+        let default = Fade::default();
+        let duration = if self.fade_whole_timer {
+            Duration::from_secs(self.timer as u64)
+        } else {
+            self.fade_s
+                .map(|seconds| Duration::from_secs(seconds as u64))
+                .unwrap_or(default.duration)
+        };
+        Fade {
+            duration,
+            curve: self.fade_curve.unwrap_or(default.curve),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -22,30 +84,159 @@ struct ShuffleRequest {
     shuffle: bool,
 }
 
+#[derive(Deserialize)]
+struct RepeatRequest {
+    mode: RepeatMode,
+}
+
+#[derive(Deserialize)]
+struct SeekRequest {
+    position_ms: u32,
+}
+
+#[derive(Deserialize)]
+struct SoloMonitorRequest {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
 #[derive(Deserialize)]
 struct LocalLibraryQuery {
     path: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct UpdatedAtQuery {
+    updated_at: u64,
+}
+
+#[derive(Deserialize)]
+struct PlaylistExportQuery {
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PlaylistImportQuery {
+    format: String,
+    /// Defaults to the name in the playlist file
+    name: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct LocalArtworkQuery {
     path: String,
 }
 
-pub fn start_http_server(playback: Arc<PlaybackController>, monitor_url: String) {
-    log::info!("Starting server @ :7755");
-    let routes = create_routes(playback, monitor_url);
+/// The services the HTTP control server exposes
+pub struct HttpServices {
+    pub auth: Arc<ApiAuth>,
+    pub playback: Arc<PlaybackController>,
+    pub routines: RoutineStore,
+    pub reloader: Arc<SettingsReloader>,
+    pub sound_detector: Arc<SoundDetector>,
+    pub ducking: Arc<DuckingController>,
+    pub health: Arc<PipelineHealth>,
+    pub recovery: Arc<MonitorRecovery>,
+    pub watchdog: Arc<MonitorWatchdog>,
+}
+
+pub fn start_http_server(
+    address: SocketAddr,
+    tls: Option<(PathBuf, PathBuf)>,
+    services: HttpServices,
+) {
+    if services.auth.enabled() {
+        log::info!("API authentication is enabled");
+    }
+    let routes = create_routes(services);
     tokio::spawn(async move {
-        warp::serve(routes).run(([0, 0, 0, 0], 7755)).await;
+        match tls {
+            Some((cert, key)) => {
+                log::info!("Starting server @ https://{}", address);
+                warp::serve(routes)
+                    .tls()
+                    .cert_path(cert)
+                    .key_path(key)
+                    .run(address)
+                    .await;
+            }
+            None => {
+                log::info!("Starting server @ http://{}", address);
+                warp::serve(routes).run(address).await;
+            }
+        }
     });
 }
 
 fn create_routes(
-    playback: Arc<PlaybackController>,
-    monitor_url: String,
+    services: HttpServices,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let HttpServices {
+        auth,
+        playback,
+        routines,
+        reloader,
+        sound_detector,
+        ducking,
+        health,
+        recovery,
+        watchdog,
+    } = services;
     let playback_filter = warp::any().map(move || playback.clone());
-    let monitor_url_filter = warp::any().map(move || monitor_url.clone());
+    let routines_filter = warp::any().map(move || routines.clone());
+    let reloader_filter = warp::any().map(move || reloader.clone());
+    // The monitors can change when the settings are reloaded
+    let monitors_filter = reloader_filter
+        .clone()
+        .map(|reloader: Arc<SettingsReloader>| reloader.settings().monitors);
+    let sound_detector_filter = warp::any().map(move || sound_detector.clone());
+    let ducking_filter = warp::any().map(move || ducking.clone());
+    let health_filter = warp::any().map(move || health.clone());
+    let recovery_filter = warp::any().map(move || recovery.clone());
+    let watchdog_filter = warp::any().map(move || watchdog.clone());
+    let auth_filter = {
+        let auth = auth.clone();
+        warp::any().map(move || auth.clone())
+    };
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(sound_detector_filter.clone())
+        .and(playback_filter.clone())
+        .and(watchdog_filter.clone())
+        .and(recovery_filter.clone())
+        .map(metrics_reply);
+
+    let pair_route = warp::path!("auth" / "pair")
+        .and(warp::post())
+        .and(warp::body::json::<PairRequest>())
+        .and(auth_filter.clone())
+        .and_then(handle_pair);
+
+    let start_pairing_route = warp::path!("auth" / "pairing")
+        .and(warp::post())
+        .and(auth_filter.clone())
+        .and_then(handle_start_pairing);
+
+    let devices_route = warp::path!("auth" / "devices")
+        .and(warp::get())
+        .and(auth_filter.clone())
+        .and_then(handle_devices);
+
+    let revoke_device_route = warp::path!("auth" / "devices" / String)
+        .and(warp::delete())
+        .and(auth_filter)
+        .and_then(handle_revoke_device);
 
     let sources_route = warp::path("sources")
         .and(warp::path::end())
@@ -98,6 +289,95 @@ fn create_routes(
         .and(playback_filter.clone())
         .and_then(handle_add_system_playlist_item);
 
+    let system_playlist_route = warp::path!("system-playlists" / String)
+        .and(warp::get())
+        .and(playback_filter.clone())
+        .and_then(handle_system_playlist);
+
+    let rename_system_playlist_route = warp::path!("system-playlists" / String)
+        .and(warp::patch())
+        .and(warp::body::json::<RenameSystemPlaylistRequest>())
+        .and(playback_filter.clone())
+        .and_then(handle_rename_system_playlist);
+
+    let delete_system_playlist_route = warp::path!("system-playlists" / String)
+        .and(warp::delete())
+        .and(warp::query::<UpdatedAtQuery>())
+        .and(playback_filter.clone())
+        .and_then(handle_delete_system_playlist);
+
+    let remove_system_playlist_item_route =
+        warp::path!("system-playlists" / String / "items" / String)
+            .and(warp::delete())
+            .and(warp::query::<UpdatedAtQuery>())
+            .and(playback_filter.clone())
+            .and_then(handle_remove_system_playlist_item);
+
+    let move_system_playlist_item_route = warp::path!("system-playlists" / String / "reorder")
+        .and(warp::post())
+        .and(warp::body::json::<MoveSystemPlaylistItemRequest>())
+        .and(playback_filter.clone())
+        .and_then(handle_move_system_playlist_item);
+
+    let duplicate_system_playlist_route = warp::path!("system-playlists" / String / "duplicate")
+        .and(warp::post())
+        .and(warp::body::json::<DuplicateSystemPlaylistRequest>())
+        .and(playback_filter.clone())
+        .and_then(handle_duplicate_system_playlist);
+
+    let export_system_playlist_route = warp::path!("system-playlists" / String / "export")
+        .and(warp::get())
+        .and(warp::query::<PlaylistExportQuery>())
+        .and(playback_filter.clone())
+        .and_then(handle_export_system_playlist);
+
+    let import_system_playlist_route = warp::path!("system-playlists" / "import")
+        .and(warp::post())
+        .and(warp::query::<PlaylistImportQuery>())
+        .and(warp::body::content_length_limit(PLAYLIST_IMPORT_LIMIT))
+        .and(warp::body::bytes())
+        .and(playback_filter.clone())
+        .and_then(handle_import_system_playlist);
+
+    let routines_route = warp::path("routines")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(routines_filter.clone())
+        .and_then(handle_routines);
+
+    let create_routine_route = warp::path("routines")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json::<RoutineRequest>())
+        .and(routines_filter.clone())
+        .and_then(handle_create_routine);
+
+    let routine_route = warp::path!("routines" / String)
+        .and(warp::get())
+        .and(routines_filter.clone())
+        .and_then(handle_routine);
+
+    let update_routine_route = warp::path!("routines" / String)
+        .and(warp::put())
+        .and(warp::body::json::<RoutineRequest>())
+        .and(routines_filter.clone())
+        .and_then(handle_update_routine);
+
+    let delete_routine_route = warp::path!("routines" / String)
+        .and(warp::delete())
+        .and(routines_filter.clone())
+        .and_then(handle_delete_routine);
+
+    let skip_routine_route = warp::path!("routines" / String / "skip")
+        .and(warp::post())
+        .and(routines_filter.clone())
+        .and_then(handle_skip_routine);
+
+    let unskip_routine_route = warp::path!("routines" / String / "skip")
+        .and(warp::delete())
+        .and(routines_filter)
+        .and_then(handle_unskip_routine);
+
     let play_ref_route = warp::path!("queue" / "play-ref")
         .and(warp::post())
         .and(warp::body::json::<PlayRefRequest>())
@@ -169,6 +449,17 @@ fn create_routes(
         .and(playback_filter.clone())
         .and_then(handle_next);
 
+    let previous_route = warp::path("previous")
+        .and(warp::post())
+        .and(playback_filter.clone())
+        .and_then(handle_previous);
+
+    let seek_route = warp::path("seek")
+        .and(warp::post())
+        .and(warp::body::json::<SeekRequest>())
+        .and(playback_filter.clone())
+        .and_then(handle_seek);
+
     let sleep_route = warp::path("sleep")
         .and(warp::post())
         .and(warp::body::json::<SleepTimerRequest>())
@@ -181,6 +472,39 @@ fn create_routes(
         .and(playback_filter.clone())
         .and_then(handle_shuffle);
 
+    let repeat_route = warp::path("repeat")
+        .and(warp::post())
+        .and(warp::body::json::<RepeatRequest>())
+        .and(playback_filter.clone())
+        .and_then(handle_repeat);
+
+    let volume_route = warp::path("volume")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(playback_filter.clone())
+        .and_then(handle_volume);
+
+    let update_volume_route = warp::path("volume")
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(warp::body::json::<VolumeRequest>())
+        .and(playback_filter.clone())
+        .and_then(handle_update_volume);
+
+    let ducking_route = warp::path("ducking")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(ducking_filter.clone())
+        .and_then(handle_ducking);
+
+    let update_ducking_route = warp::path("ducking")
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(warp::body::json::<DuckingSettings>())
+        .and(reloader_filter.clone())
+        .and(ducking_filter)
+        .and_then(handle_update_ducking);
+
     let status_route = warp::path("status")
         .and(warp::get())
         .and(playback_filter.clone())
@@ -192,10 +516,56 @@ fn create_routes(
         .and_then(handle_status);
 
     let monitor_route = warp::path("monitor")
+        .and(warp::path::end())
         .and(warp::get())
-        .and(monitor_url_filter)
+        .and(monitors_filter.clone())
+        .and(recovery_filter.clone())
+        .and(playback_filter.clone())
         .and_then(handle_monitor);
 
+    let monitors_route = warp::path("monitors")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(monitors_filter)
+        .and(recovery_filter)
+        .and(playback_filter.clone())
+        .and_then(handle_monitors);
+
+    let solo_monitor_route = warp::path!("monitors" / "solo")
+        .and(warp::put())
+        .and(warp::body::json::<SoloMonitorRequest>())
+        .and(playback_filter.clone())
+        .and_then(handle_solo_monitor);
+
+    let update_monitor_route = warp::path!("monitors" / String)
+        .and(warp::put())
+        .and(warp::body::json::<MonitorChannelRequest>())
+        .and(playback_filter.clone())
+        .and_then(handle_update_monitor);
+
+    let reload_route = warp::path!("admin" / "reload")
+        .and(warp::post())
+        .and(reloader_filter.clone())
+        .and_then(handle_reload);
+
+    let settings_route = warp::path("settings")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(reloader_filter.clone())
+        .and_then(handle_settings);
+
+    let update_settings_route = warp::path("settings")
+        .and(warp::path::end())
+        .and(warp::patch())
+        .and(warp::body::json::<serde_json::Map<String, serde_json::Value>>())
+        .and(reloader_filter)
+        .and_then(handle_update_settings);
+
+    let pipeline_health_route = warp::path!("pipeline" / "health")
+        .and(warp::get())
+        .and(health_filter)
+        .and_then(handle_pipeline_health);
+
     let status_stream_route = warp::path("status_stream")
         .and(warp::get())
         .and(playback_filter.clone())
@@ -206,14 +576,51 @@ fn create_routes(
         .and(playback_filter)
         .map(status_stream_reply);
 
-    sources_route
+    let sound_event_stream_route = warp::path("sound_event_stream")
+        .and(warp::get())
+        .and(sound_detector_filter)
+        .map(sound_event_stream_reply);
+
+    let monitor_alarm_stream_route = warp::path("monitor_alarm_stream")
+        .and(warp::get())
+        .and(watchdog_filter)
+        .map(monitor_alarm_stream_reply);
+
+    let stream_routes = status_stream_route
+        .or(audio_status_stream_route)
+        .or(sound_event_stream_route)
+        .or(monitor_alarm_stream_route);
+
+    let system_playlist_routes = system_playlists_route
+        .or(create_system_playlist_route)
+        .or(add_system_playlist_item_route)
+        .or(system_playlist_route)
+        .or(rename_system_playlist_route)
+        .or(delete_system_playlist_route)
+        .or(remove_system_playlist_item_route)
+        .or(move_system_playlist_item_route)
+        .or(duplicate_system_playlist_route)
+        .or(export_system_playlist_route)
+        .or(import_system_playlist_route)
+        .map(warp::reply::Reply::into_response)
+        .boxed();
+
+    let api_routes = sources_route
+        .or(start_pairing_route)
+        .or(devices_route)
+        .or(revoke_device_route)
         .or(local_library_route)
         .or(local_artwork_route)
         .or(youtube_library_route)
         .or(youtube_artwork_route)
-        .or(system_playlists_route)
-        .or(create_system_playlist_route)
-        .or(add_system_playlist_item_route)
+        .or(system_playlist_routes)
+        .or(routines_route)
+        .or(create_routine_route)
+        .or(routine_route)
+        .or(update_routine_route)
+        .or(delete_routine_route)
+        .or(skip_routine_route)
+        .or(unskip_routine_route)
         .or(play_ref_route)
         .or(queue_route)
         .or(clear_queue_route)
@@ -227,16 +634,124 @@ fn create_routes(
         .or(play_route)
         .or(pause_route)
         .or(next_route)
+        .or(previous_route)
+        .or(seek_route)
         .or(status_route)
         .or(audio_status_route)
         .or(sleep_route)
         .or(shuffle_route)
+        .or(repeat_route)
+        .or(volume_route)
+        .or(update_volume_route)
+        .or(ducking_route)
+        .or(update_ducking_route)
         .or(monitor_route)
-        .or(status_stream_route)
-        .or(audio_status_stream_route)
+        .or(monitors_route)
+        .or(solo_monitor_route)
+        .or(update_monitor_route)
+        .or(reload_route)
+        .or(settings_route)
+        .or(update_settings_route)
+        .or(pipeline_health_route)
+        .or(metrics_route);
+
+    // The SSE streams are opened by clients that cannot always set headers, so they also accept
+    // the token as a query parameter
+    pair_route
+        .or(authorized_stream(auth.clone()).and(stream_routes))
+        .or(authorized(auth).and(api_routes))
+        .recover(handle_rejection)
+        .with(warp::log::custom(|info| {
+            metrics::record_http_request(
+                info.method().as_str(),
+                info.status().as_u16(),
+                info.elapsed(),
+            )
+        }))
         .boxed()
 }
 
+fn authorized(auth: Arc<ApiAuth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let authorized = auth.is_authorized(header.as_deref().and_then(bearer_token));
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn authorized_stream(auth: Arc<ApiAuth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<TokenQuery>())
+        .and_then(move |header: Option<String>, query: TokenQuery| {
+            let token = header
+                .as_deref()
+                .and_then(bearer_token)
+                .or(query.token.as_deref());
+            let authorized = auth.is_authorized(token);
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn bearer_token(header: &str) -> Option<&str> {
+    header
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<Response<Body>, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        let mut response = error_status("Unauthorized", StatusCode::UNAUTHORIZED);
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        return Ok(response);
+    }
+    Err(rejection)
+}
+
+async fn handle_pair(req: PairRequest, auth: Arc<ApiAuth>) -> Result<Response<Body>, Rejection> {
+    match auth.pair(req) {
+        Ok(token) => Ok(no_store(json_status(&token, StatusCode::CREATED))),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::FORBIDDEN)),
+    }
+}
+
+async fn handle_start_pairing(auth: Arc<ApiAuth>) -> Result<Response<Body>, Rejection> {
+    Ok(no_store(json_status(&auth.start_pairing(), StatusCode::OK)))
+}
+
+async fn handle_devices(auth: Arc<ApiAuth>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&auth.devices()))
+}
+
+async fn handle_revoke_device(
+    device: String,
+    auth: Arc<ApiAuth>,
+) -> Result<Response<Body>, Rejection> {
+    let device = urlencoding::decode(&device)
+        .map(|device| device.into_owned())
+        .unwrap_or(device);
+    match auth.revoke(&device) {
+        Ok(()) => Ok(ok_status("revoked")),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::NOT_FOUND)),
+    }
+}
+
 async fn handle_sources(playback: Arc<PlaybackController>) -> Result<impl Reply, Rejection> {
     Ok(warp::reply::json(&playback.sources()))
 }
@@ -339,10 +854,266 @@ async fn handle_add_system_playlist_item(
 ) -> Result<Response<Body>, Rejection> {
     match playback.system_playlists().add_item(&playlist_id, req) {
         Ok(playlist) => Ok(json_status(&playlist, StatusCode::OK)),
+        Err(e) => Ok(playlist_error_status(e)),
+    }
+}
+
+async fn handle_system_playlist(
+    playlist_id: String,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback.system_playlists().get(&playlist_id) {
+        Some(playlist) => Ok(json_status(&playlist, StatusCode::OK)),
+        None => Ok(playlist_error_status(
+            PlaylistEditError::NotFound(playlist_id).into(),
+        )),
+    }
+}
+
+async fn handle_rename_system_playlist(
+    playlist_id: String,
+    req: RenameSystemPlaylistRequest,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback.system_playlists().rename(&playlist_id, req) {
+        Ok(playlist) => Ok(json_status(&playlist, StatusCode::OK)),
+        Err(e) => Ok(playlist_error_status(e)),
+    }
+}
+
+async fn handle_delete_system_playlist(
+    playlist_id: String,
+    query: UpdatedAtQuery,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback
+        .system_playlists()
+        .delete(&playlist_id, query.updated_at)
+    {
+        Ok(()) => Ok(ok_status("deleted")),
+        Err(e) => Ok(playlist_error_status(e)),
+    }
+}
+
+async fn handle_remove_system_playlist_item(
+    playlist_id: String,
+    item_id: String,
+    query: UpdatedAtQuery,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback
+        .system_playlists()
+        .remove_item(&playlist_id, &item_id, query.updated_at)
+    {
+        Ok(playlist) => Ok(json_status(&playlist, StatusCode::OK)),
+        Err(e) => Ok(playlist_error_status(e)),
+    }
+}
+
+async fn handle_move_system_playlist_item(
+    playlist_id: String,
+    req: MoveSystemPlaylistItemRequest,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback.system_playlists().move_item(&playlist_id, req) {
+        Ok(playlist) => Ok(json_status(&playlist, StatusCode::OK)),
+        Err(e) => Ok(playlist_error_status(e)),
+    }
+}
+
+async fn handle_duplicate_system_playlist(
+    playlist_id: String,
+    req: DuplicateSystemPlaylistRequest,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback.system_playlists().duplicate(&playlist_id, req) {
+        Ok(playlist) => Ok(json_status(&playlist, StatusCode::CREATED)),
+        Err(e) => Ok(playlist_error_status(e)),
+    }
+}
+
+async fn handle_export_system_playlist(
+    playlist_id: String,
+    query: PlaylistExportQuery,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    let format = match PlaylistFormat::from_name(query.format.as_deref().unwrap_or("m3u8")) {
+        Ok(format) => format,
+        Err(e) => return Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+    let Some(playlist) = playback.system_playlists().get(&playlist_id) else {
+        return Ok(playlist_error_status(
+            PlaylistEditError::NotFound(playlist_id).into(),
+        ));
+    };
+
+    match playlist_formats::export(&playlist, format, &playlist_libraries(&playback)) {
+        Ok(content) => {
+            let filename = format!(
+                "{}.{}",
+                playlist
+                    .name
+                    .chars()
+                    .map(|c| if c.is_alphanumeric() || " -_".contains(c) {
+                        c
+                    } else {
+                        '_'
+                    })
+                    .collect::<String>(),
+                format.extension()
+            );
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, format.content_type())
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{filename}\""),
+                )
+                .body(Body::from(content))
+                .unwrap())
+        }
+        Err(e) => Ok(error_status(
+            &e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn handle_import_system_playlist(
+    query: PlaylistImportQuery,
+    body: warp::hyper::body::Bytes,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    let parsed = match PlaylistFormat::from_name(&query.format).and_then(|format| {
+        let content = std::str::from_utf8(&body)
+            .map_err(|_| anyhow::anyhow!("Playlist file is not valid UTF-8"))?;
+        playlist_formats::parse(format, content)
+    }) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+
+    let (items, unresolved) =
+        playlist_formats::resolve(parsed.entries, &playlist_libraries(&playback));
+    if items.is_empty() {
+        return Ok(json_status(
+            &serde_json::json!({
+                "error": "None of the playlist entries could be found",
+                "unresolved": unresolved,
+            }),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let name = query
+        .name
+        .or(parsed.name)
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "Imported playlist".to_string());
+    match playback.system_playlists().import(name, items) {
+        Ok(playlist) => Ok(json_status(
+            &serde_json::json!({ "playlist": playlist, "unresolved": unresolved }),
+            StatusCode::CREATED,
+        )),
+        Err(e) => Ok(error_status(
+            &e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+fn playlist_libraries(playback: &PlaybackController) -> PlaylistLibraries {
+    PlaylistLibraries {
+        local: playback.local_library(),
+        youtube: playback.youtube_library(),
+        system: playback.system_playlists(),
+    }
+}
+
+/// A conflicting edit returns the current playlist, so the client can merge its change
+fn playlist_error_status(e: anyhow::Error) -> Response<Body> {
+    match e.downcast_ref::<PlaylistEditError>() {
+        Some(PlaylistEditError::Conflict(playlist)) => json_status(
+            &serde_json::json!({ "error": e.to_string(), "playlist": playlist }),
+            StatusCode::CONFLICT,
+        ),
+        Some(PlaylistEditError::NotFound(_)) => error_status(&e.to_string(), StatusCode::NOT_FOUND),
+        None => error_status(&e.to_string(), StatusCode::BAD_REQUEST),
+    }
+}
+
+async fn handle_routines(routines: RoutineStore) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&routines.list()))
+}
+
+async fn handle_routine(id: String, routines: RoutineStore) -> Result<Response<Body>, Rejection> {
+    match routines.get(&id) {
+        Some(routine) => Ok(json_status(&routine, StatusCode::OK)),
+        None => Ok(error_status(
+            &format!("Unknown routine: {id}"),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+async fn handle_create_routine(
+    req: RoutineRequest,
+    routines: RoutineStore,
+) -> Result<Response<Body>, Rejection> {
+    match routines.create(req) {
+        Ok(routine) => Ok(json_status(&routine, StatusCode::CREATED)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn handle_update_routine(
+    id: String,
+    req: RoutineRequest,
+    routines: RoutineStore,
+) -> Result<Response<Body>, Rejection> {
+    if routines.get(&id).is_none() {
+        return Ok(error_status(
+            &format!("Unknown routine: {id}"),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+    match routines.update(&id, req) {
+        Ok(routine) => Ok(json_status(&routine, StatusCode::OK)),
         Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
     }
 }
 
+async fn handle_delete_routine(
+    id: String,
+    routines: RoutineStore,
+) -> Result<Response<Body>, Rejection> {
+    match routines.delete(&id) {
+        Ok(()) => Ok(ok_status("deleted")),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::NOT_FOUND)),
+    }
+}
+
+async fn handle_skip_routine(
+    id: String,
+    routines: RoutineStore,
+) -> Result<Response<Body>, Rejection> {
+    match routines.set_skip_next(&id, true) {
+        Ok(routine) => Ok(json_status(&routine, StatusCode::OK)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::NOT_FOUND)),
+    }
+}
+
+async fn handle_unskip_routine(
+    id: String,
+    routines: RoutineStore,
+) -> Result<Response<Body>, Rejection> {
+    match routines.set_skip_next(&id, false) {
+        Ok(routine) => Ok(json_status(&routine, StatusCode::OK)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::NOT_FOUND)),
+    }
+}
+
 async fn handle_play_ref(
     req: PlayRefRequest,
     playback: Arc<PlaybackController>,
@@ -448,17 +1219,175 @@ async fn handle_status(playback: Arc<PlaybackController>) -> Result<impl Reply,
     Ok(warp::reply::json(&playback.current_info()))
 }
 
-async fn handle_monitor(monitor_url: String) -> Result<impl Reply, Rejection> {
+async fn handle_monitor(
+    monitors: Vec<MonitorSettings>,
+    recovery: Arc<MonitorRecovery>,
+    playback: Arc<PlaybackController>,
+) -> Result<impl Reply, Rejection> {
     Ok(warp::reply::json(&serde_json::json!({
-        "url": monitor_url
+        "url": monitors.first().map(|monitor| monitor.url.clone()),
+        "monitors": monitor_statuses(&monitors, &recovery, &playback),
     })))
 }
 
+async fn handle_monitors(
+    monitors: Vec<MonitorSettings>,
+    recovery: Arc<MonitorRecovery>,
+    playback: Arc<PlaybackController>,
+) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&monitor_statuses(
+        &monitors, &recovery, &playback,
+    )))
+}
+
+async fn handle_update_monitor(
+    name: String,
+    req: MonitorChannelRequest,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    let name = urlencoding::decode(&name)
+        .map(|name| name.into_owned())
+        .unwrap_or(name);
+    match playback.update_monitor_channel(&name, req) {
+        Ok(channel) => Ok(json_status(&channel, StatusCode::OK)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn handle_solo_monitor(
+    req: SoloMonitorRequest,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback.solo_monitor_channel(req.name.as_deref()) {
+        Ok(channels) => Ok(json_status(&channels, StatusCode::OK)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn handle_reload(reloader: Arc<SettingsReloader>) -> Result<Response<Body>, Rejection> {
+    match tokio::task::spawn_blocking(move || reloader.reload()).await {
+        Ok(Ok(summary)) => Ok(json_status(&summary, StatusCode::OK)),
+        Ok(Err(e)) => {
+            log::warn!("{:#}", e);
+            Ok(error_status(&format!("{:#}", e), StatusCode::BAD_REQUEST))
+        }
+        Err(e) => Ok(error_status(
+            &e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn handle_pipeline_health(health: Arc<PipelineHealth>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&health.report()))
+}
+
+async fn handle_settings(reloader: Arc<SettingsReloader>) -> Result<Response<Body>, Rejection> {
+    match reloader.public_settings() {
+        Ok(settings) => Ok(json_status(&settings, StatusCode::OK)),
+        Err(e) => Ok(error_status(
+            &e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn handle_update_settings(
+    patch: serde_json::Map<String, serde_json::Value>,
+    reloader: Arc<SettingsReloader>,
+) -> Result<Response<Body>, Rejection> {
+    let result = {
+        let reloader = reloader.clone();
+        tokio::task::spawn_blocking(move || reloader.update(patch)).await
+    };
+    let summary = match result {
+        Ok(Ok(summary)) => summary,
+        Ok(Err(e)) => {
+            log::warn!("{:#}", e);
+            return Ok(error_status(&format!("{:#}", e), StatusCode::BAD_REQUEST));
+        }
+        Err(e) => {
+            return Ok(error_status(
+                &e.to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+
+    match reloader.public_settings() {
+        Ok(settings) => Ok(json_status(
+            &serde_json::json!({
+                "settings": settings,
+                "changed": summary.changed,
+                "applied": summary.applied,
+                "requires_restart": summary.requires_restart,
+                "no_effect": summary.no_effect,
+            }),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(error_status(
+            &e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// The configured monitors combined with their current gain, mute and connection state
+fn monitor_statuses(
+    monitors: &[MonitorSettings],
+    recovery: &MonitorRecovery,
+    playback: &PlaybackController,
+) -> Vec<serde_json::Value> {
+    let channels = playback.monitor_channels();
+    let connections = recovery.connections();
+    monitors
+        .iter()
+        .filter_map(|monitor| {
+            let channel = channels
+                .iter()
+                .find(|channel| channel.name == monitor.name)?;
+            Some(serde_json::json!({
+                "name": monitor.name,
+                "url": monitor.url,
+                "gain": channel.gain,
+                "muted": channel.muted,
+                "soloed": channel.soloed,
+                "connection": connections
+                    .iter()
+                    .find(|(name, _)| *name == monitor.name)
+                    .map(|(_, connection)| connection),
+            }))
+        })
+        .collect()
+}
+
 async fn handle_sleep(
     req: SleepTimerRequest,
     playback: Arc<PlaybackController>,
 ) -> Result<Response<Body>, Rejection> {
-    match playback.sleep(req.timer).await {
+    let result = match req.mode {
+        SleepRequestMode::Time => playback.sleep(req.timer, req.fade()).await,
+        SleepRequestMode::EndOfTrack => {
+            playback
+                .sleep_at_end_of_track(SleepTimerMode::Tracks {
+                    remaining_tracks: 1,
+                })
+                .await
+        }
+        SleepRequestMode::Tracks => {
+            playback
+                .sleep_at_end_of_track(SleepTimerMode::Tracks {
+                    remaining_tracks: req.tracks.unwrap_or(1),
+                })
+                .await
+        }
+        SleepRequestMode::EndOfQueue => {
+            playback
+                .sleep_at_end_of_track(SleepTimerMode::EndOfQueue)
+                .await
+        }
+    };
+    match result {
         Ok(()) => Ok(json_status(&playback.current_info(), StatusCode::OK)),
         Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
     }
@@ -474,6 +1403,67 @@ async fn handle_shuffle(
     }
 }
 
+async fn handle_repeat(
+    req: RepeatRequest,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback.repeat(req.mode).await {
+        Ok(info) => Ok(json_status(&info, StatusCode::OK)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn handle_volume(playback: Arc<PlaybackController>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&playback.volume()))
+}
+
+async fn handle_update_volume(
+    req: VolumeRequest,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback.set_volume(req).await {
+        Ok(levels) => Ok(json_status(&levels, StatusCode::OK)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn handle_ducking(ducking: Arc<DuckingController>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&ducking.status()))
+}
+
+/// Stored as a settings override, so the change survives a restart and shows up in GET /settings
+async fn handle_update_ducking(
+    req: DuckingSettings,
+    reloader: Arc<SettingsReloader>,
+    ducking: Arc<DuckingController>,
+) -> Result<Response<Body>, Rejection> {
+    if let Err(e) = req.validate() {
+        return Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST));
+    }
+    let mut patch = serde_json::Map::new();
+    match serde_json::to_value(&req) {
+        Ok(settings) => patch.insert("ducking".to_string(), settings),
+        Err(e) => {
+            return Ok(error_status(
+                &e.to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+
+    match tokio::task::spawn_blocking(move || reloader.update(patch)).await {
+        Ok(Ok(_)) => Ok(json_status(&ducking.status(), StatusCode::OK)),
+        Ok(Err(e)) => {
+            log::warn!("{:#}", e);
+            Ok(error_status(&format!("{:#}", e), StatusCode::BAD_REQUEST))
+        }
+        Err(e) => Ok(error_status(
+            &e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
 async fn handle_play(playback: Arc<PlaybackController>) -> Result<Response<Body>, Rejection> {
     match playback.play().await {
         Ok(()) => Ok(json_status(&playback.current_info(), StatusCode::OK)),
@@ -495,6 +1485,23 @@ async fn handle_next(playback: Arc<PlaybackController>) -> Result<Response<Body>
     }
 }
 
+async fn handle_previous(playback: Arc<PlaybackController>) -> Result<Response<Body>, Rejection> {
+    match playback.previous().await {
+        Ok(()) => Ok(json_status(&playback.current_info(), StatusCode::OK)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn handle_seek(
+    req: SeekRequest,
+    playback: Arc<PlaybackController>,
+) -> Result<Response<Body>, Rejection> {
+    match playback.seek(req.position_ms).await {
+        Ok(()) => Ok(json_status(&playback.current_info(), StatusCode::OK)),
+        Err(e) => Ok(error_status(&e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
 fn status_stream_reply(playback: Arc<PlaybackController>) -> impl Reply {
     let mut info_channel: watch::Receiver<_> = playback.info_channel();
     let event_stream: futures_util::stream::BoxStream<
@@ -524,6 +1531,114 @@ fn status_stream_reply(playback: Arc<PlaybackController>) -> impl Reply {
     warp::sse::reply(warp::sse::keep_alive().stream(event_stream))
 }
 
+fn sound_event_stream_reply(sound_detector: Arc<SoundDetector>) -> impl Reply {
+    let mut events = sound_detector.subscribe();
+    let event_stream: futures_util::stream::BoxStream<
+        'static,
+        Result<warp::sse::Event, std::convert::Infallible>,
+    > = async_stream::stream! {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let json = serde_json::to_string(&event).unwrap_or_else(|_| "{}".to_string());
+                    yield Ok(warp::sse::Event::default().data(json));
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("Sound event stream lagged, skipped {skipped} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+    .boxed();
+
+    warp::sse::reply(warp::sse::keep_alive().stream(event_stream))
+}
+
+fn metrics_reply(
+    sound_detector: Arc<SoundDetector>,
+    playback: Arc<PlaybackController>,
+    watchdog: Arc<MonitorWatchdog>,
+    recovery: Arc<MonitorRecovery>,
+) -> impl Reply {
+    let mut writer = MetricsWriter::default();
+    writer.recorded();
+
+    let loudness = *sound_detector.loudness_channel().borrow();
+    writer.gauge(
+        "carechords_monitor_rms_db",
+        "Loudness (RMS) of the loudest camera in dBFS",
+        loudness.rms_db,
+    );
+    writer.gauge(
+        "carechords_monitor_peak_db",
+        "Peak level of the loudest camera in dBFS",
+        loudness.peak_db,
+    );
+    writer.gauge(
+        "carechords_monitor_alarm",
+        "Whether the monitor watchdog alarm is raised",
+        if watchdog.alarm_active() { 1.0 } else { 0.0 },
+    );
+
+    let connections = recovery.connections();
+    let connected: Vec<(&str, f64)> = connections
+        .iter()
+        .map(|(name, connection)| {
+            let connected = *connection == MonitorConnection::Connected;
+            (name.as_str(), if connected { 1.0 } else { 0.0 })
+        })
+        .collect();
+    writer.labeled_gauge(
+        "carechords_monitor_connected",
+        "Whether a monitor camera is connected",
+        "monitor",
+        &connected,
+    );
+
+    let levels = playback.volume();
+    writer.labeled_gauge(
+        "carechords_volume",
+        "Current volume levels",
+        "channel",
+        &[
+            ("master", levels.master),
+            ("music", levels.music),
+            ("monitor", levels.monitor),
+        ],
+    );
+
+    warp::reply::with_header(
+        writer.finish(),
+        header::CONTENT_TYPE,
+        "text/plain; version=0.0.4",
+    )
+}
+
+fn monitor_alarm_stream_reply(watchdog: Arc<MonitorWatchdog>) -> impl Reply {
+    let mut events = watchdog.subscribe();
+    let event_stream: futures_util::stream::BoxStream<
+        'static,
+        Result<warp::sse::Event, std::convert::Infallible>,
+    > = async_stream::stream! {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let json = serde_json::to_string(&event).unwrap_or_else(|_| "{}".to_string());
+                    yield Ok(warp::sse::Event::default().data(json));
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("Monitor alarm stream lagged, skipped {skipped} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+    .boxed();
+
+    warp::sse::reply(warp::sse::keep_alive().stream(event_stream))
+}
+
 fn ok_status(status: &str) -> Response<Body> {
     json_status(&serde_json::json!({ "status": status }), StatusCode::OK)
 }